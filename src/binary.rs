@@ -0,0 +1,499 @@
+//! Compact binary StackTraceFlow format
+//!
+//! The file starts with MAGIC, followed by a sequence of events. Every event starts with a tag
+//! byte. Function names, file names and records are interned: each is defined once by a
+//! FUNCTION, FILE or RECORD event and afterwards referred to by its index in the respective
+//! table. All numbers are unsigned LEB128 varints.
+//!
+//! * FUNCTION length bytes - append a function name to the function table
+//! * FILE length bytes - append a file name to the file table
+//! * RECORD function file line column last_line last_column - append to the record table
+//! * PUSH record - a function was entered
+//! * POP - the function on top of the stack returned
+//! * PUSH_AT record timestamp - like PUSH, at the given time in nanoseconds
+//! * POP_AT timestamp - like POP, at the given time in nanoseconds
+
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::data::Record;
+use crate::input::Input;
+use crate::trace::{self, Event, Format, ParseError, RecordId, TraceReader};
+
+pub const MAGIC: &[u8] = b"STFBIN\x00\x01";
+
+const FUNCTION: u8 = 1;
+const FILE:     u8 = 2;
+const RECORD:   u8 = 3;
+const PUSH:     u8 = 4;
+const POP:      u8 = 5;
+const PUSH_AT:  u8 = 6;
+const POP_AT:   u8 = 7;
+
+/// Longest function or file name accepted, so that a corrupted length is caught early
+pub const MAX_NAME: u64 = 1 << 20;
+
+pub fn read_varint(input: &mut dyn Read) -> std::io::Result<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte)?;
+        if shift >= 64 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "varint too long"));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub fn write_varint(output: &mut dyn Write, mut value: u64) -> std::io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return output.write_all(&[byte]);
+        }
+        output.write_all(&[byte | 0x80])?;
+    }
+}
+
+pub struct BinaryReader {
+    input:     Input,
+    line_no:   usize,
+    offset:    u64,
+    timestamp: Option<u64>,
+    functions: Vec<String>,
+    files:     Vec<String>,
+    records:   Vec<Record>,
+    /// Set after the end of the file or an unrecoverable error
+    done:      bool,
+    /// Set after seeking. All the definitions are known by then, so they are skipped
+    frozen:    bool,
+}
+
+impl BinaryReader {
+    pub fn new(mut input: Input) -> Result<Self, ParseError> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(ParseError::BadEvent{
+                line_no: 0,
+                reason: "not a binary StackTraceFlow file",
+            });
+        }
+        Ok(BinaryReader{
+            input: input,
+            line_no: 0,
+            offset: 0,
+            timestamp: None,
+            functions: Vec::new(),
+            files: Vec::new(),
+            records: Vec::new(),
+            done: false,
+            frozen: false,
+        })
+    }
+
+    fn bad(&self, reason: &'static str) -> ParseError {
+        ParseError::BadEvent{line_no: self.line_no, reason: reason}
+    }
+
+    fn number(&mut self) -> Result<u64, ParseError> {
+        read_varint(&mut self.input).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => self.bad("truncated event"),
+            std::io::ErrorKind::InvalidData   => self.bad("malformed number"),
+            _                                 => ParseError::Io(e),
+        })
+    }
+
+    /// Read a reference to one of the tables, making sure it is already defined
+    fn index(&mut self, table_len: usize) -> Result<usize, ParseError> {
+        let index = self.number()? as usize;
+        if index >= table_len {
+            return Err(self.bad("reference to an undefined entry"));
+        }
+        Ok(index)
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        let len = self.number()?;
+        if len > MAX_NAME {
+            return Err(self.bad("name too long"));
+        }
+        // The length may be corrupted, so the buffer only grows as far as the file goes
+        let mut bytes = Vec::new();
+        Read::by_ref(&mut self.input).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(self.bad("truncated event"));
+        }
+        String::from_utf8(bytes).map_err(|_| self.bad("invalid UTF-8 in a name"))
+    }
+
+    fn read_event(&mut self) -> Result<Option<Event>, ParseError> {
+        loop {
+            self.offset = self.input.offset();
+            if self.input.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+            let mut tag = [0u8];
+            self.input.read_exact(&mut tag)?;
+            self.line_no += 1;
+            self.timestamp = None;
+            match tag[0] {
+                FUNCTION if self.frozen => { self.string()?; },
+                FILE if self.frozen => { self.string()?; },
+                RECORD if self.frozen => {
+                    for _ in 0..6 {
+                        self.number()?;
+                    }
+                },
+                FUNCTION => {
+                    let function = self.string()?;
+                    self.functions.push(function);
+                },
+                FILE => {
+                    let file = self.string()?;
+                    self.files.push(file);
+                },
+                RECORD => {
+                    let function = self.index(self.functions.len())?;
+                    let file = self.index(self.files.len())?;
+                    let record = Record{
+                        function: self.functions[function].clone(),
+                        file: self.files[file].clone(),
+                        line: self.number()? as usize,
+                        column: self.number()? as usize,
+                        last_line: self.number()? as usize,
+                        last_column: self.number()? as usize,
+                    };
+                    self.records.push(record);
+                },
+                PUSH => {
+                    let id = self.index(self.records.len())?;
+                    return Ok(Some(Event::Push(id)));
+                },
+                POP => return Ok(Some(Event::Pop(None))),
+                PUSH_AT => {
+                    let id = self.index(self.records.len())?;
+                    self.timestamp = Some(self.number()?);
+                    return Ok(Some(Event::Push(id)));
+                },
+                POP_AT => {
+                    self.timestamp = Some(self.number()?);
+                    return Ok(Some(Event::Pop(None)));
+                },
+                _ => return Err(self.bad("unknown event tag")),
+            }
+        }
+    }
+}
+
+impl TraceReader for BinaryReader {
+    fn next_event(&mut self) -> Option<Result<Event, ParseError>> {
+        if self.done {
+            return None;
+        }
+        match self.read_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                // There is no way to find the beginning of the next event
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+
+    fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    fn line_no(&self) -> usize {
+        self.line_no
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), ParseError> {
+        self.input.seek(offset)?;
+        self.done = false;
+        self.frozen = true;
+        Ok(())
+    }
+
+    fn restore_records(&mut self, records: Vec<Record>) {
+        self.records = records;
+        self.frozen = true;
+    }
+
+    fn format(&self) -> Format {
+        Format::Binary
+    }
+}
+
+pub struct BinaryWriter<W: Write> {
+    output:    W,
+    functions: HashMap<String, u64>,
+    files:     HashMap<String, u64>,
+    /// Maps ids of the source reader to indices in the record table
+    records:   HashMap<RecordId, u64>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    pub fn new(mut output: W) -> std::io::Result<Self> {
+        output.write_all(MAGIC)?;
+        Ok(BinaryWriter{
+            output: output,
+            functions: HashMap::new(),
+            files: HashMap::new(),
+            records: HashMap::new(),
+        })
+    }
+
+    fn intern(
+        output: &mut W,
+        table: &mut HashMap<String, u64>,
+        tag: u8,
+        name: &str,
+    ) -> std::io::Result<u64> {
+        if let Some(index) = table.get(name) {
+            return Ok(*index);
+        }
+        output.write_all(&[tag])?;
+        write_varint(output, name.len() as u64)?;
+        output.write_all(name.as_bytes())?;
+        let index = table.len() as u64;
+        table.insert(name.to_string(), index);
+        Ok(index)
+    }
+
+    pub fn push(
+        &mut self,
+        id: RecordId,
+        record: &Record,
+        timestamp: Option<u64>,
+    ) -> std::io::Result<()> {
+        let index = match self.records.get(&id) {
+            Some(index) => *index,
+            None => {
+                let function = Self::intern(
+                    &mut self.output, &mut self.functions, FUNCTION, &record.function)?;
+                let file = Self::intern(&mut self.output, &mut self.files, FILE, &record.file)?;
+                self.output.write_all(&[RECORD])?;
+                for n in &[function, file, record.line as u64, record.column as u64,
+                           record.last_line as u64, record.last_column as u64] {
+                    write_varint(&mut self.output, *n)?;
+                }
+                let index = self.records.len() as u64;
+                self.records.insert(id, index);
+                index
+            },
+        };
+        match timestamp {
+            None => {
+                self.output.write_all(&[PUSH])?;
+                write_varint(&mut self.output, index)
+            },
+            Some(timestamp) => {
+                self.output.write_all(&[PUSH_AT])?;
+                write_varint(&mut self.output, index)?;
+                write_varint(&mut self.output, timestamp)
+            },
+        }
+    }
+
+    pub fn pop(&mut self, timestamp: Option<u64>) -> std::io::Result<()> {
+        match timestamp {
+            None => self.output.write_all(&[POP]),
+            Some(timestamp) => {
+                self.output.write_all(&[POP_AT])?;
+                write_varint(&mut self.output, timestamp)
+            },
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Destination of a conversion
+enum Output<W: Write> {
+    Text(W),
+    Binary(BinaryWriter<W>),
+}
+
+/// Timestamp as it ends a line of the text format
+fn format_timestamp(timestamp: Option<u64>) -> String {
+    timestamp.map_or(String::new(), |timestamp| format!(" {}", timestamp))
+}
+
+impl<W: Write> Output<W> {
+    fn push(
+        &mut self,
+        id: RecordId,
+        record: &Record,
+        timestamp: Option<u64>,
+    ) -> std::io::Result<()> {
+        match self {
+            Output::Text(output)   => writeln!(
+                output, "+{}{}", trace::format_line(record), format_timestamp(timestamp)),
+            Output::Binary(writer) => writer.push(id, record, timestamp),
+        }
+    }
+
+    fn pop(&mut self, record: &Record, timestamp: Option<u64>) -> std::io::Result<()> {
+        match self {
+            Output::Text(output)   => writeln!(
+                output, "-{}{}", trace::format_line(record), format_timestamp(timestamp)),
+            Output::Binary(writer) => writer.pop(timestamp),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Text(output)   => output.flush(),
+            Output::Binary(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Convert a StackTraceFlow file to the given format, or to the other one if not specified
+pub fn convert(input: &Path, output: &Path, to: Option<Format>) -> Result<(), ParseError> {
+    let mut reader = trace::open(input)?;
+    let to = to.unwrap_or(match reader.format() {
+        Format::Text   => Format::Binary,
+        Format::Binary => Format::Text,
+    });
+    let file = BufWriter::new(std::fs::File::create(output)?);
+    let mut output = match to {
+        Format::Text   => Output::Text(file),
+        Format::Binary => Output::Binary(BinaryWriter::new(file)?),
+    };
+    let mut stack: Vec<RecordId> = Vec::new();
+
+    while let Some(event) = reader.next_event() {
+        match event? {
+            Event::Push(id) => {
+                output.push(id, &reader.records()[id], reader.timestamp())?;
+                stack.push(id);
+            },
+            Event::Pop(id) => {
+                // Binary files do not say which function returns, so the missing '-' lines of
+                // a text file have to be closed explicitly
+                let pos = match id {
+                    Some(id) => stack.iter().rposition(|i| *i == id).ok_or_else(
+                        || ParseError::UnbalancedPop{
+                            line_no: reader.line_no(),
+                            record: reader.records()[id].to_string(),
+                            expected: stack.last().map_or(
+                                String::new(), |top| reader.records()[*top].to_string()),
+                        })?,
+                    None => stack.len().checked_sub(1).ok_or_else(
+                        || ParseError::EmptyStackPop{line_no: reader.line_no()})?,
+                };
+                // The calls closed implicitly end at the same time as the one closed explicitly
+                for top in stack.drain(pos..).rev() {
+                    output.pop(&reader.records()[top], reader.timestamp())?;
+                }
+            },
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("stacktraceflow-{}-{}", std::process::id(), name))
+    }
+
+    /// Nested and repeated calls, some of them timed
+    fn sample() -> String {
+        let mut text = String::new();
+        text += "+main @src/main.rs:10:1: 42:2 1000\n";
+        for i in 0..50 {
+            text += &format!("+parse @src/parser.rs:5:5: 30:6 {}\n", 2000 + i * 10);
+            text += &format!("+next @src/lexer.rs:100:9: 120:10 {}\n", 2001 + i * 10);
+            text += &format!("-next @src/lexer.rs:100:9: 120:10 {}\n", 2005 + i * 10);
+            text += &format!("-parse @src/parser.rs:5:5: 30:6 {}\n", 2009 + i * 10);
+        }
+        text += "-main @src/main.rs:10:1: 42:2 9000\n";
+        text
+    }
+
+    #[test]
+    fn text_binary_text_round_trip() {
+        let text = temp_file("round-trip.txt");
+        let binary = temp_file("round-trip.bin");
+        let back = temp_file("round-trip-back.txt");
+        std::fs::write(&text, sample()).unwrap();
+
+        convert(&text, &binary, None).unwrap();
+        convert(&binary, &back, None).unwrap();
+        let converted = std::fs::read(&binary).unwrap();
+        let result = std::fs::read_to_string(&back).unwrap();
+        for file in &[&text, &binary, &back] {
+            let _ = std::fs::remove_file(file);
+        }
+
+        assert!(converted.starts_with(MAGIC));
+        assert_eq!(result, sample());
+        // Names are written once, and the events take a few bytes each
+        assert!(converted.len() * 5 < sample().len());
+    }
+
+    #[test]
+    fn corrupted_name_length_is_an_error() {
+        let mut file = MAGIC.to_vec();
+        file.push(FUNCTION);
+        write_varint(&mut file, u64::max_value() >> 1).unwrap();
+        file.extend_from_slice(b"main");
+        let path = temp_file("corrupted.bin");
+        std::fs::write(&path, &file).unwrap();
+        let mut reader = trace::open(&path).unwrap();
+        let event = reader.next_event();
+        let _ = std::fs::remove_file(&path);
+
+        match event {
+            Some(Err(ParseError::BadEvent{..})) => {},
+            other => panic!("expected a bad event, got {:?}", other.map(|e| e.is_ok())),
+        }
+    }
+
+    #[test]
+    fn truncated_name_is_an_error() {
+        let mut file = MAGIC.to_vec();
+        file.push(FUNCTION);
+        write_varint(&mut file, 1000).unwrap();
+        file.extend_from_slice(b"main");
+        let path = temp_file("truncated.bin");
+        std::fs::write(&path, &file).unwrap();
+        let mut reader = trace::open(&path).unwrap();
+        let event = reader.next_event();
+        let _ = std::fs::remove_file(&path);
+
+        match event {
+            Some(Err(ParseError::BadEvent{reason, ..})) => assert_eq!(reason, "truncated event"),
+            other => panic!("expected a truncated event, got {:?}", other.map(|e| e.is_ok())),
+        }
+    }
+}
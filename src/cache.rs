@@ -0,0 +1,287 @@
+//! Sidecar file next to the trace, keeping its index between runs
+//!
+//! The cache is only used if the trace still has the same size, modification time and hash of
+//! its beginning and end. Otherwise it is rebuilt. A cache that cannot be read back, e.g.
+//! because it was damaged, is ignored the same way.
+//!
+//! Only lazy mode uses the cache: the other modes build the whole tree from the trace itself.
+
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::binary::{read_varint, write_varint, MAX_NAME};
+use crate::data::Record;
+use crate::index::{Call, TraceIndex};
+
+const MAGIC: &[u8] = b"STFIDX\x00\x02";
+
+/// How much of the beginning and of the end of the trace is hashed
+const HASHED: u64 = 1 << 20;
+
+/// Identity of the trace the cache was built for
+#[derive(PartialEq, Debug)]
+struct Key {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u64,
+    hash: u64,
+}
+
+/// Index and records read back from the cache
+pub struct Cached {
+    pub index: TraceIndex,
+    pub records: Vec<Record>,
+    /// Number of malformed lines skipped when the index was built
+    pub skipped: usize,
+}
+
+pub fn path(trace: &Path) -> PathBuf {
+    let mut name = trace.file_name().unwrap_or_default().to_os_string();
+    name.push(".stfindex");
+    trace.with_file_name(name)
+}
+
+/// FNV-1a, which unlike std's hasher is guaranteed to stay the same across Rust versions
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+fn key(trace: &Path) -> std::io::Result<Key> {
+    let metadata = std::fs::metadata(trace)?;
+    let mtime = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let size = metadata.len();
+
+    let mut file = std::fs::File::open(trace)?;
+    let mut buffer = Vec::new();
+    (&mut file).take(HASHED).read_to_end(&mut buffer)?;
+    if size > HASHED {
+        file.seek(SeekFrom::Start(std::cmp::max(HASHED, size - HASHED)))?;
+        file.take(HASHED).read_to_end(&mut buffer)?;
+    }
+    Ok(Key{
+        size: size,
+        mtime_secs: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos() as u64,
+        hash: fnv1a(0xcbf29ce484222325, &buffer),
+    })
+}
+
+fn write_string(output: &mut dyn Write, s: &str) -> std::io::Result<()> {
+    write_varint(output, s.len() as u64)?;
+    output.write_all(s.as_bytes())
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+fn read_string(input: &mut dyn Read) -> std::io::Result<String> {
+    let len = read_varint(input)?;
+    if len > MAX_NAME {
+        return Err(invalid("name too long"));
+    }
+    // The length may be damaged, so the buffer only grows as far as the file goes
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(invalid("truncated name"));
+    }
+    String::from_utf8(bytes).map_err(
+        |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Write the cache under a temporary name first, so that an interrupted write never leaves a
+/// truncated cache behind
+pub fn save(
+    trace: &Path,
+    index: &TraceIndex,
+    records: &[Record],
+    skipped: usize,
+) -> std::io::Result<()> {
+    // Still ending with .stfindex, so that it is not mistaken for the trace of a thread
+    let mut name = trace.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.stfindex", std::process::id()));
+    let temporary = trace.with_file_name(name);
+    let result = write(trace, &temporary, index, records, skipped)
+        .and_then(|_| std::fs::rename(&temporary, path(trace)));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+fn write(
+    trace: &Path,
+    output: &Path,
+    index: &TraceIndex,
+    records: &[Record],
+    skipped: usize,
+) -> std::io::Result<()> {
+    let key = key(trace)?;
+    let mut output = BufWriter::new(std::fs::File::create(output)?);
+    output.write_all(MAGIC)?;
+    for n in &[key.size, key.mtime_secs, key.mtime_nanos, key.hash, skipped as u64] {
+        write_varint(&mut output, *n)?;
+    }
+
+    write_varint(&mut output, records.len() as u64)?;
+    for record in records {
+        write_string(&mut output, &record.function)?;
+        write_string(&mut output, &record.file)?;
+        for n in &[record.line, record.column, record.last_line, record.last_column] {
+            write_varint(&mut output, *n as u64)?;
+        }
+    }
+
+    // Calls are ordered by their start, so store the differences to keep the numbers small
+    write_varint(&mut output, index.calls.len() as u64)?;
+    let mut previous_start = 0;
+    for call in &index.calls {
+        write_varint(&mut output, call.start - previous_start)?;
+        write_varint(&mut output, call.end - call.start)?;
+        write_varint(&mut output, call.size)?;
+        output.write_all(&[call.never_returned as u8])?;
+        // 0 stands for no duration
+        write_varint(&mut output, call.duration.map_or(0, |duration| duration + 1))?;
+        previous_start = call.start;
+    }
+    output.flush()
+}
+
+fn read(trace: &Path) -> std::io::Result<Option<Cached>> {
+    let mut input = BufReader::new(std::fs::File::open(path(trace))?);
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+    let cached_key = Key{
+        size: read_varint(&mut input)?,
+        mtime_secs: read_varint(&mut input)?,
+        mtime_nanos: read_varint(&mut input)?,
+        hash: read_varint(&mut input)?,
+    };
+    if cached_key != key(trace)? {
+        return Ok(None);
+    }
+    let skipped = read_varint(&mut input)? as usize;
+
+    let mut records = Vec::new();
+    for _ in 0..read_varint(&mut input)? {
+        records.push(Record{
+            function: read_string(&mut input)?,
+            file: read_string(&mut input)?,
+            line: read_varint(&mut input)? as usize,
+            column: read_varint(&mut input)? as usize,
+            last_line: read_varint(&mut input)? as usize,
+            last_column: read_varint(&mut input)? as usize,
+        });
+    }
+
+    let count = read_varint(&mut input)?;
+    let mut calls = Vec::new();
+    let mut previous_start: u64 = 0;
+    for i in 0..count {
+        let start = previous_start.checked_add(read_varint(&mut input)?)
+            .ok_or_else(|| invalid("offset out of range"))?;
+        let end = start.checked_add(read_varint(&mut input)?)
+            .ok_or_else(|| invalid("offset out of range"))?;
+        // The subtree has to hold at least the call itself and fit in the index, or walking the
+        // children would go astray
+        let size = read_varint(&mut input)?;
+        if size == 0 || size > count - i {
+            return Err(invalid("subtree size out of range"));
+        }
+        let mut never_returned = [0u8];
+        input.read_exact(&mut never_returned)?;
+        let duration = read_varint(&mut input)?.checked_sub(1);
+        calls.push(Call{
+            start: start,
+            end: end,
+            size: size,
+            never_returned: never_returned[0] != 0,
+            duration: duration,
+        });
+        previous_start = start;
+    }
+    Ok(Some(Cached{index: TraceIndex{calls: calls}, records: records, skipped: skipped}))
+}
+
+/// Read the cache of the trace, if there is one and it is up to date
+pub fn load(trace: &Path) -> Option<Cached> {
+    read(trace).unwrap_or(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(start: u64, end: u64, size: u64) -> Call {
+        let duration = Some(end - start);
+        Call{start: start, end: end, size: size, never_returned: false, duration: duration}
+    }
+
+    /// Save the cache of a small trace, let `damage` change it and load it back
+    fn reload(name: &str, damage: &dyn Fn(&mut Vec<u8>)) -> Option<Cached> {
+        let trace = std::env::temp_dir().join(
+            format!("stacktraceflow-{}-{}", std::process::id(), name));
+        std::fs::write(&trace, "+main @src/main.rs:1\n+a @src/a.rs:2\n-a @src/a.rs:2\n").unwrap();
+        let index = TraceIndex{calls: vec![call(0, 60, 2), call(21, 36, 1)]};
+        let records = vec![Record{
+            function: "main".to_owned(),
+            file: "src/main.rs".to_owned(),
+            line: 1,
+            column: 0,
+            last_line: 0,
+            last_column: 0,
+        }];
+        save(&trace, &index, &records, 0).unwrap();
+        let mut bytes = std::fs::read(path(&trace)).unwrap();
+        damage(&mut bytes);
+        std::fs::write(path(&trace), &bytes).unwrap();
+        let cached = load(&trace);
+        let _ = std::fs::remove_file(path(&trace));
+        let _ = std::fs::remove_file(&trace);
+        cached
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let cached = reload("intact", &|_| {}).unwrap();
+        assert_eq!(cached.records[0].function, "main");
+        assert_eq!(cached.index.calls.len(), 2);
+        assert_eq!(cached.index.calls[1].start, 21);
+        assert_eq!(cached.index.calls[1].end, 36);
+        assert_eq!(cached.index.children(0), vec![1]);
+    }
+
+    #[test]
+    fn truncated_cache_is_ignored() {
+        assert!(reload("truncated", &|bytes| bytes.truncate(bytes.len() - 3)).is_none());
+    }
+
+    #[test]
+    fn subtree_size_out_of_range_is_ignored() {
+        // Each call takes 5 bytes at the end, the size of the first one being the third
+        let damage = |bytes: &mut Vec<u8>| {
+            let at = bytes.len() - 10 + 2;
+            bytes[at] = 3;
+        };
+        assert!(reload("size", &damage).is_none());
+    }
+
+    #[test]
+    fn name_length_out_of_range_is_ignored() {
+        // The function name follows the key, the number of skipped lines and of records
+        let damage = |bytes: &mut Vec<u8>| {
+            let at = find(bytes, b"main") - 1;
+            bytes.splice(at..at + 1, vec![0xff, 0xff, 0xff, 0xff, 0x0f]);
+        };
+        assert!(reload("name", &damage).is_none());
+    }
+
+    fn find(bytes: &[u8], needle: &[u8]) -> usize {
+        bytes.windows(needle.len()).position(|window| window == needle).unwrap()
+    }
+}
@@ -0,0 +1,214 @@
+//! Reverse view of the trace, answering "who calls this?"
+//!
+//! Every distinct function is at the top level. Below it are its callers, below them the
+//! callers' callers, and so on, each with the number of calls that came through that path.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use cursive_tree_view::Placement;
+
+use crate::config::Configuration;
+use crate::data::{Action, Caller, Record, Row, TreeType};
+use crate::init::{skip_or_fail, ReadReport};
+use crate::threads;
+use crate::trace::{self, Event, ParseError};
+
+struct Node {
+    /// Index into `Callers::records`
+    record: usize,
+    count: usize,
+    /// Maps records of the callers to their nodes
+    children: HashMap<usize, usize>,
+}
+
+pub struct Callers {
+    /// Records of all the files, each stored once
+    records: Vec<Record>,
+    ids: HashMap<Record, usize>,
+    nodes: Vec<Node>,
+    /// Maps records to the nodes at the top level
+    roots: HashMap<usize, usize>,
+}
+
+impl Callers {
+    fn new() -> Self {
+        Callers{records: Vec::new(), ids: HashMap::new(), nodes: Vec::new(), roots: HashMap::new()}
+    }
+
+    fn intern(&mut self, record: &Record) -> usize {
+        if let Some(id) = self.ids.get(record) {
+            return *id;
+        }
+        let id = self.records.len();
+        self.records.push(record.clone());
+        self.ids.insert(record.clone(), id);
+        id
+    }
+
+    fn new_node(&mut self, record: usize) -> usize {
+        self.nodes.push(Node{record: record, count: 0, children: HashMap::new()});
+        self.nodes.len() - 1
+    }
+
+    /// Count the call on top of the stack, together with `depth - 1` levels of its callers
+    fn add_call(&mut self, stack: &[usize], depth: usize) {
+        let (&callee, callers) = match stack.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+        let mut node = match self.roots.get(&callee) {
+            Some(&node) => node,
+            None => {
+                let node = self.new_node(callee);
+                self.roots.insert(callee, node);
+                node
+            },
+        };
+        self.nodes[node].count += 1;
+        for &caller in callers.iter().rev().take(depth.saturating_sub(1)) {
+            node = match self.nodes[node].children.get(&caller) {
+                Some(&child) => child,
+                None => {
+                    let child = self.new_node(caller);
+                    self.nodes[node].children.insert(caller, child);
+                    child
+                },
+            };
+            self.nodes[node].count += 1;
+        }
+    }
+
+    /// Nodes ordered by the number of calls, most first
+    fn sorted(&self, nodes: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut nodes: Vec<usize> = nodes.collect();
+        nodes.sort_by_key(|&node| std::cmp::Reverse(self.nodes[node].count));
+        nodes
+    }
+
+    fn insert(
+        &self,
+        tree: &mut TreeType,
+        max_size: usize,
+        node: usize,
+        placement: Placement,
+        row: usize,
+    ) -> Option<usize> {
+        if tree.len() >= max_size {
+            return None;
+        }
+        let item = Row::Caller(Caller{
+            record: self.records[self.nodes[node].record].clone(),
+            count: self.nodes[node].count,
+        });
+        let new_row = tree.insert_item(item, placement, row)?;
+        for child in self.sorted(self.nodes[node].children.values().cloned()) {
+            self.insert(tree, max_size, child, Placement::LastChild, new_row);
+        }
+        Some(new_row)
+    }
+
+    /// Fill an empty tree with the callers, as long as it is smaller than `max_size`
+    pub fn populate(&self, tree: &mut TreeType, max_size: usize) {
+        let mut previous = None;
+        for root in self.sorted(self.roots.values().cloned()) {
+            let row = match previous {
+                None => self.insert(tree, max_size, root, Placement::LastChild, 0),
+                Some(previous) => self.insert(tree, max_size, root, Placement::After, previous),
+            };
+            if row.is_some() {
+                previous = row;
+            }
+        }
+    }
+}
+
+/// Call on the stack while reading
+struct Frame {
+    /// Index into `Callers::records`
+    record: usize,
+    /// Length of the stack of shown calls below this one
+    shown: usize,
+    /// Removed together with its children by one of the actions, or below such a call
+    removed: bool,
+}
+
+/// Read the file, or the files of all the threads, and count the calls by their callers up to
+/// `configuration.depth` levels.
+///
+/// The actions hiding every call of a function are applied: a deleted call is left out of the
+/// stacks of its children, and a removed one is left out together with them. Actions on a
+/// single call in the calls view have no counterpart here
+pub fn build(configuration: &Configuration) -> Result<Callers, ParseError> {
+    let files: Vec<PathBuf> = match threads::discover(&configuration.file)? {
+        None => vec![configuration.file.clone()],
+        Some(threads) => threads.into_iter().map(|thread| thread.file).collect(),
+    };
+    let mut callers = Callers::new();
+    // Problems have been reported when the tree was loaded already
+    let mut report = ReadReport::new();
+
+    for file in files {
+        let mut reader = trace::open(&file)?;
+        // Maps ids of the reader to those of `callers`, together with the action hiding the
+        // record, if any
+        let mut ids: Vec<Option<(usize, Option<&Action>)>> = Vec::new();
+        let mut stack: Vec<Frame> = Vec::new();
+        // The calls on the stack that are not hidden
+        let mut shown: Vec<usize> = Vec::new();
+        while let Some(event) = reader.next_event() {
+            match event {
+                Ok(Event::Push(record)) => {
+                    if ids.len() <= record {
+                        ids.resize(record + 1, None);
+                    }
+                    let (id, action) = match ids[record] {
+                        Some(known) => known,
+                        None => {
+                            let record = &reader.records()[record];
+                            let action = configuration.actions.iter().find(
+                                |act| act.hides(record));
+                            (callers.intern(record), action)
+                        },
+                    };
+                    ids[record] = Some((id, action));
+                    let removed = stack.last().map_or(false, |frame| frame.removed)
+                        || action.map_or(false, Action::is_recursive);
+                    stack.push(Frame{record: id, shown: shown.len(), removed: removed});
+                    if !removed && action.is_none() {
+                        shown.push(id);
+                        callers.add_call(&shown, configuration.depth as usize);
+                    }
+                },
+                Ok(Event::Pop(record)) => {
+                    // Close the missing '-' lines up to the call this line belongs to
+                    let pos = match record {
+                        Some(record) => ids.get(record).cloned().unwrap_or(None).and_then(
+                            |(id, _)| stack.iter().rposition(|frame| frame.record == id)),
+                        None => stack.len().checked_sub(1),
+                    };
+                    match (pos, record, stack.last()) {
+                        (Some(pos), _, _) => {
+                            shown.truncate(stack[pos].shown);
+                            stack.truncate(pos);
+                        },
+                        (None, Some(record), Some(top)) => {
+                            let error = ParseError::UnbalancedPop{
+                                line_no: reader.line_no(),
+                                record: reader.records()[record].to_string(),
+                                expected: callers.records[top.record].to_string(),
+                            };
+                            skip_or_fail(configuration, &mut report, error)?;
+                        },
+                        (None, _, _) => {
+                            let error = ParseError::EmptyStackPop{line_no: reader.line_no()};
+                            skip_or_fail(configuration, &mut report, error)?;
+                        },
+                    }
+                },
+                Err(e) => skip_or_fail(configuration, &mut report, e)?,
+            }
+        }
+    }
+    Ok(callers)
+}
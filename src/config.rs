@@ -4,7 +4,11 @@ use std::path::PathBuf;
 use std::io::{Write, Read};
 use regex::Regex;
 
-use crate::data::Action;
+use crate::data::{Action, Bookmark};
+use crate::export::Mode;
+use crate::remap::{self, Rule};
+use crate::session::ViewState;
+use crate::trace::Format;
 
 #[derive(Clone)]
 pub struct Configuration {
@@ -12,9 +16,17 @@ pub struct Configuration {
     pub file: std::path::PathBuf,
     pub depth: u16,
     pub max_size: usize,
+    /// Row selected by older versions, used if there is no `view.selected`
     pub selected: usize,
+    pub view: ViewState,
     pub actions: Vec<Action>,
+    pub bookmarks: Vec<Bookmark>,
     pub only: Vec<Regex>,
+    pub lenient: bool,
+    pub lazy: bool,
+    pub sort_by_duration: bool,
+    pub aggregate: bool,
+    pub fold: FoldRules,
     pub source_code_info: Option<SourceCodeInfo>,
 }
 
@@ -22,19 +34,172 @@ pub struct Configuration {
 pub struct SourceCodeInfo {
     /// Directory containing the source code
     pub dir: std::path::PathBuf,
-    /// Command to open source code in external editor
-    pub editor: String,
+    /// Command to open source code in external editor, used by `Backend::Command`
+    pub editor: Option<String>,
+    /// How the calls are opened in the editor
+    pub backend: Backend,
+    /// Open every call selected in the editor, not only when asked to
+    pub follow: bool,
+    /// Rules mapping the paths in the trace to local files, the first matching one applies
+    pub remap: Vec<Rule>,
+    /// Rules from the command line, tried before `remap` and not saved
+    pub remap_args: Vec<Rule>,
+}
+
+/// Way of talking to the editor
+#[derive(Clone, PartialEq, Debug)]
+pub enum Backend {
+    /// Run the `editor` command for every call opened. If `wait`, the tree waits for it to exit,
+    /// e.g. for an editor running in the same terminal
+    Command{wait: bool},
+    /// Send the calls to a running Neovim through its RPC socket: a path, or host:port. None
+    /// means the socket of the Neovim the program runs in, from `$NVIM`
+    Neovim(Option<String>),
+    /// Send the calls to a running Emacs server with emacsclient
+    Emacs,
+    /// Open the calls in a running Visual Studio Code with `code --goto`
+    VsCode,
+}
+
+impl Backend {
+    fn new(name: Option<&String>, socket: Option<&String>, wait: bool) -> Backend {
+        let backend = match name.map(String::as_str) {
+            None | Some("command") => return Backend::Command{wait: wait},
+            Some("nvim") => Backend::Neovim(socket.cloned()),
+            Some("emacs") => Backend::Emacs,
+            Some("code") => Backend::VsCode,
+            Some(name) => panic!(
+                "Unknown editor backend '{}', expected 'command', 'nvim', 'emacs' or 'code'", name
+            ),
+        };
+        if wait {
+            panic!("The editor_wait option only applies to the 'command' editor backend");
+        }
+        backend
+    }
+
+    /// Whether the backend opens the calls in an editor running already rather than starting
+    /// one for each
+    pub fn reuses_editor(&self) -> bool {
+        match self {
+            Backend::Command{..} => false,
+            _ => true,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::Command{..} => "command",
+            Backend::Neovim(_) => "nvim",
+            Backend::Emacs => "emacs",
+            Backend::VsCode => "code",
+        }
+    }
 }
 
 impl SourceCodeInfo {
-    fn new_option(args: &Cli, file: &FileConfig) -> Option<SourceCodeInfo> {
+    /// Path of the file after remapping, relative to `dir` unless remapped to an absolute one
+    pub fn file(&self, file: &str) -> String {
+        remap::file(self.remap_args.iter().chain(&self.remap), file)
+    }
+
+    /// Where the file is on this machine
+    pub fn path(&self, file: &str) -> PathBuf {
+        remap::path(self.remap_args.iter().chain(&self.remap), &self.dir, file)
+    }
+
+    fn new_option(args: &Cli, file: &mut FileConfig) -> Option<SourceCodeInfo> {
         let dir = args.dir.as_ref().or_else(|| file.dir.as_ref());
         let editor = args.editor.as_ref().or_else(|| file.editor.as_ref());
-        match (dir, editor) {
-            (None, None) => return None,
-            (Some(d), Some(e)) => return Some(SourceCodeInfo{dir: d.clone(), editor: e.clone()}),
-            (Some(_), None) => panic!("editor option must be specified when dir is specified"),
-            (None, Some(_)) => panic!("dir option must be specified when editor is specified"),
+        let backend = Backend::new(
+            args.editor_backend.as_ref().or_else(|| file.editor_backend.as_ref()),
+            args.nvim_socket.as_ref().or_else(|| file.nvim_socket.as_ref()),
+            args.editor_wait || file.editor_wait.unwrap_or(false),
+        );
+        let configured = editor.is_some() || backend.reuses_editor();
+        let follow = args.editor_follow || file.editor_follow.unwrap_or(false);
+        if follow && !backend.reuses_editor() {
+            // A new editor for every row selected would be unusable
+            panic!("The editor_follow option needs the 'nvim', 'emacs' or 'code' editor backend");
+        }
+        match (dir, configured) {
+            (None, false) => return None,
+            (Some(d), true) => return Some(SourceCodeInfo{
+                dir: d.clone(),
+                editor: editor.cloned(),
+                backend: backend,
+                follow: follow,
+                remap: rpl(&mut file.remap).unwrap_or(Vec::new()),
+                remap_args: args.remap_path_prefix.clone(),
+            }),
+            (Some(_), false) => panic!("editor option must be specified when dir is specified"),
+            (None, true) => panic!("dir option must be specified when editor is specified"),
+        }
+    }
+}
+
+/// Which runs of consecutive identical sibling calls are folded into one row
+#[derive(Clone)]
+pub struct FoldRules {
+    /// Shortest run that is folded. 0 disables folding
+    pub min_run: usize,
+    /// Fold only the functions matching any of those. All of them if empty
+    pub functions: Vec<Regex>,
+    /// Never fold the functions matching any of those
+    pub except: Vec<Regex>,
+    /// Fold chains of recursive calls into the outermost call
+    pub recursion: bool,
+}
+
+/// FoldRules as written in the config file
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct FoldConfig {
+    /// Shortest run that is folded. Defaults to 0, which disables folding
+    min_run: Option<usize>,
+    /// Fold only the functions matching any of those regexes. All of them if empty
+    functions: Option<Vec<String>>,
+    /// Never fold the functions matching any of those regexes
+    except: Option<Vec<String>>,
+    /// Fold chains of recursive calls into the outermost call. Defaults to false
+    recursion: Option<bool>,
+}
+
+/// Compile the regexes of the given config option
+fn regexes(strings: Option<Vec<String>>) -> Vec<Regex> {
+    strings.unwrap_or(Vec::new()).iter().map(|s: &String| {
+        Regex::new(s).expect(&format!("Cannot parse regex '{}", &s))
+    }).collect()
+}
+
+impl FoldRules {
+    fn new(args: &Cli, file: Option<FoldConfig>) -> FoldRules {
+        let file = file.unwrap_or_default();
+        FoldRules{
+            min_run: args.fold_min_run.or(file.min_run).unwrap_or(0),
+            functions: regexes(file.functions),
+            except: regexes(file.except),
+            recursion: args.fold_recursion || file.recursion.unwrap_or(false),
+        }
+    }
+
+    /// Whether the rules are all left at their defaults, so that they need not be saved
+    fn is_default(&self) -> bool {
+        self.min_run == 0 && self.functions.is_empty() && self.except.is_empty()
+            && !self.recursion
+    }
+}
+
+impl From<FoldRules> for FoldConfig {
+    fn from(rules: FoldRules) -> Self {
+        let strings = |regexes: Vec<Regex>| if regexes.is_empty() { None } else {
+            Some(regexes.iter().map(|r| r.to_string()).collect())
+        };
+        // Only what differs from the defaults
+        FoldConfig{
+            min_run: if rules.min_run == 0 { None } else { Some(rules.min_run) },
+            functions: strings(rules.functions),
+            except: strings(rules.except),
+            recursion: if rules.recursion { Some(true) } else { None },
         }
     }
 }
@@ -45,8 +210,7 @@ fn rpl<T: Default>(source: &mut T) -> T {
 }
 
 impl Configuration {
-    pub fn load() -> Configuration {
-        let mut args = Cli::from_args();
+    pub fn load(mut args: Cli) -> Configuration {
         let mut file_config = FileConfig::new();
         let config_path = match args.config {
             Some(ref path) => {
@@ -68,6 +232,15 @@ impl Configuration {
         }).collect();
         new_only_rx.append(&mut args.only);
 
+        let lazy = args.lazy || file_config.lazy.unwrap_or(false);
+        let aggregate = args.aggregate || file_config.aggregate.unwrap_or(false);
+        if lazy && !new_only_rx.is_empty() {
+            panic!("'only' patterns cannot be used together with the lazy option");
+        }
+        if aggregate && (lazy || !new_only_rx.is_empty()) {
+            panic!("The aggregate option cannot be used together with 'only' patterns or lazy");
+        }
+
         Configuration{
             config:     config_path,
             file:       args.file.as_ref().or_else(|| file_config.file.as_ref()).expect(
@@ -77,9 +250,19 @@ impl Configuration {
             ),
             max_size:   args.max_size.or_else(|| file_config.max_size).unwrap_or(10_000),
             selected:   file_config.selected.unwrap_or(1),
+            view:       rpl(&mut file_config.view).unwrap_or_default(),
             actions:    rpl(&mut file_config.actions).unwrap_or(Vec::new()),
+            // A bookmark without steps could never be found
+            bookmarks:  rpl(&mut file_config.bookmarks).unwrap_or(Vec::new()).into_iter()
+                .filter(|bookmark: &Bookmark| !bookmark.path.steps.is_empty()).collect(),
             only:       new_only_rx,
-            source_code_info: SourceCodeInfo::new_option(&args, &file_config),
+            lenient:    args.lenient || file_config.lenient.unwrap_or(false),
+            lazy:       lazy,
+            sort_by_duration: args.sort_by_duration
+                || file_config.sort_by_duration.unwrap_or(false),
+            aggregate:  aggregate,
+            fold:       FoldRules::new(&args, rpl(&mut file_config.fold)),
+            source_code_info: SourceCodeInfo::new_option(&args, &mut file_config),
         }
     }
 
@@ -104,13 +287,19 @@ impl Configuration {
 
 /// Analyze and pretty-print StackTraceFlow data from a Rust program
 #[derive(StructOpt)]
-struct Cli {
+pub struct Cli {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
     /// Configuration file. Optional if all the required parameters are supplied on the command
     /// line
     #[structopt(parse(from_os_str), short, long)]
     config: Option<std::path::PathBuf>,
 
     /// File with the StackTraceFlow data
+    ///
+    /// A directory or a glob pattern opens the files of all the threads together, each under a
+    /// top-level node. In a directory, files named like 'stacktraceflow.ThreadId(N).txt' are used.
     #[structopt(parse(from_os_str), short, long)]
     file: Option<std::path::PathBuf>,
 
@@ -122,12 +311,49 @@ struct Cli {
 
     /// Command to open source code file in external editor.
     ///
-    /// %F stands for filename, %L stands for line number
+    /// %F stands for filename, %L stands for line number, %C stands for column number, %E stands
+    /// for the line where the function ends, %f for the function name and %D for the directory.
+    /// Words are split and quoted like in a shell, but no other shell syntax is interpreted.
     ///
     /// Must be provided iff dir is also provided.
     #[structopt(short, long)]
     editor: Option<String>,
 
+    /// Wait for the editor command to exit instead of going back to the tree right away
+    ///
+    /// Only for the 'command' backend: the others hand the call over to an editor running
+    /// already and do not block.
+    #[structopt(long)]
+    editor_wait: bool,
+
+    /// How to open the calls: 'command' (the default) runs the editor command, 'nvim' sends them
+    /// to a running Neovim, 'emacs' to a running Emacs server and 'code' to Visual Studio Code
+    ///
+    /// The backends other than 'command' reuse the editor window that is already open and do not
+    /// need the editor option.
+    #[structopt(long)]
+    editor_backend: Option<String>,
+
+    /// Socket of the Neovim to use with the 'nvim' backend: a path, or host:port
+    ///
+    /// Defaults to $NVIM, which is set in the terminals opened inside Neovim.
+    #[structopt(long)]
+    nvim_socket: Option<String>,
+
+    /// Open every call selected in the editor, not only when 'e' is pressed
+    ///
+    /// Needs a backend other than 'command', which reuses the editor that is open already.
+    #[structopt(long)]
+    editor_follow: bool,
+
+    /// Rewrite the source paths starting with FROM to start with TO instead, given as FROM=TO
+    ///
+    /// Can be repeated, the first matching rule applies. Relative paths, rewritten or not, are
+    /// looked up in 'dir'. Tried before the 'remap' rules of the config file, which can also use
+    /// regexes, and not saved to it.
+    #[structopt(long)]
+    remap_path_prefix: Vec<Rule>,
+
     /// How deep should the printed tree be
     ///
     /// This is the depth of the entire tree if there are no 'only' patterns supplied.
@@ -146,6 +372,87 @@ struct Cli {
     /// If any is specified, trim the tree to show only parents and children of the matching nodes
     #[structopt(long)]
     only: Vec<Regex>,
+
+    /// Skip malformed lines of the StackTraceFlow file instead of failing
+    ///
+    /// A summary of the skipped lines is shown when the tree opens.
+    #[structopt(long)]
+    lenient: bool,
+
+    /// Index the file and read the subtrees only when they are expanded
+    ///
+    /// This keeps multi-gigabyte traces navigable in their entirety. Nodes deeper than 'depth'
+    /// are loaded on demand instead of being cut off, as long as the tree stays within
+    /// 'max-size'. Requires an uncompressed file and cannot be combined with 'only'.
+    ///
+    /// The index is cached next to the trace and reused until the trace changes. Without this
+    /// option, the whole trace is read on every launch.
+    #[structopt(long)]
+    lazy: bool,
+
+    /// Show the longest calls first instead of in chronological order
+    ///
+    /// Requires a trace with timestamps. Can be toggled with 's'.
+    #[structopt(long)]
+    sort_by_duration: bool,
+
+    /// Merge the calls made through the same path into one node with a call count
+    ///
+    /// Nodes can be expanded back into the individual calls with 'x'. Can be toggled with 'a'.
+    /// Cannot be combined with 'only' or 'lazy'.
+    #[structopt(long)]
+    aggregate: bool,
+
+    /// Fold runs of at least this many consecutive calls of the same function, with the same
+    /// subtree, into one row
+    ///
+    /// Defaults to 0, which disables folding. Which functions are folded can be configured in
+    /// the config file. Does not apply to 'lazy' and 'aggregate'.
+    #[structopt(long)]
+    fold_min_run: Option<usize>,
+
+    /// Fold recursive calls into the outer call of the same function
+    ///
+    /// A call to a function that is already on the call stack, directly or through other
+    /// functions, is then shown as part of the outer call of the function, labelled with the
+    /// depth of the recursion. Chains folded like that count as one level for 'depth'. Does not
+    /// apply to 'lazy' and 'aggregate'.
+    #[structopt(long)]
+    fold_recursion: bool,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Convert a StackTraceFlow file between the text and the binary format
+    #[structopt(name = "convert")]
+    Convert {
+        /// File to convert, in either format, possibly compressed
+        #[structopt(parse(from_os_str))]
+        input: std::path::PathBuf,
+
+        /// Where to write the converted file
+        #[structopt(parse(from_os_str))]
+        output: std::path::PathBuf,
+
+        /// Format of the output: 'text' or 'binary'. Defaults to the one the input is not in
+        #[structopt(long)]
+        to: Option<Format>,
+    },
+
+    /// Write the tree, as it would be shown with the configuration, as a Graphviz DOT file
+    ///
+    /// The actions are applied and the calls inside collapsed nodes are left out.
+    #[structopt(name = "export")]
+    Export {
+        /// Where to write the DOT file
+        #[structopt(parse(from_os_str))]
+        output: std::path::PathBuf,
+
+        /// 'graph' for a node per function, with edges weighted by the number of calls, or
+        /// 'tree' for a node per row, in the order of the calls
+        #[structopt(long, default_value = "graph")]
+        mode: Mode,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -157,24 +464,62 @@ struct FileConfig {
 
     /// Command to open source code file in external editor.
     ///
-    /// %F stands for filename, %L stands for line number
+    /// %F stands for filename, %L stands for line number, %C stands for column number, %E stands
+    /// for the line where the function ends, %f for the function name and %D for the directory
     editor: Option<String>,
 
+    /// Wait for the editor command to exit instead of going back to the tree right away
+    editor_wait: Option<bool>,
+
+    /// How to open the calls: 'command', 'nvim', 'emacs' or 'code'
+    editor_backend: Option<String>,
+
+    /// Socket of the Neovim to use with the 'nvim' backend: a path, or host:port
+    nvim_socket: Option<String>,
+
+    /// Open every call selected in the editor
+    editor_follow: Option<bool>,
+
     /// How deep should the printed tree be
     depth: Option<u16>,
 
     /// Truncate the tree if it grows beyond this size
     max_size: Option<usize>,
 
-    /// Cursor position
+    /// Cursor position, as a row. `view.selected` takes precedence
     selected: Option<usize>,
 
+    /// Skip malformed lines of the StackTraceFlow file instead of failing
+    lenient: Option<bool>,
+
+    /// Index the file and read the subtrees only when they are expanded
+    lazy: Option<bool>,
+
+    /// Show the longest calls first instead of in chronological order
+    sort_by_duration: Option<bool>,
+
+    /// Merge the calls made through the same path into one node with a call count
+    aggregate: Option<bool>,
+
     /// If any is specified, trim the tree to show only parents and children of the nodes matching
     /// the regexes
     only: Option<Vec<String>>,
 
+    /// Folding of runs of consecutive identical sibling calls. A table, so it has to come after
+    /// all the plain values
+    fold: Option<FoldConfig>,
+
+    /// Rules mapping the source paths in the trace to local files
+    remap: Option<Vec<Rule>>,
+
     /// Modifications to the tree (removals) performed by the user
     actions: Option<Vec<Action>>,
+
+    /// Calls pinned by the user, with notes
+    bookmarks: Option<Vec<Bookmark>>,
+
+    /// Selected row and nodes collapsed or expanded by the user
+    view: Option<ViewState>,
 }
 
 impl FileConfig {
@@ -183,10 +528,22 @@ impl FileConfig {
             file: None,
             dir: None,
             editor: None,
+            editor_wait: None,
+            editor_backend: None,
+            nvim_socket: None,
+            editor_follow: None,
             depth: None,
             max_size: None,
             selected: None,
+            lenient: None,
+            lazy: None,
+            sort_by_duration: None,
+            aggregate: None,
+            fold: None,
+            remap: None,
             actions: None,
+            bookmarks: None,
+            view: None,
             only: None
         }
     }
@@ -199,11 +556,36 @@ impl From<Configuration> for FileConfig {
         FileConfig {
             file: Some(rpl(&mut conf.file)),
             dir: sci.as_mut().map(|sci: &mut SourceCodeInfo| rpl(&mut sci.dir)),
-            editor: sci.as_mut().map(|sci: &mut SourceCodeInfo| rpl(&mut sci.editor)),
+            editor: sci.as_mut().and_then(|sci: &mut SourceCodeInfo| rpl(&mut sci.editor)),
+            editor_wait: sci.as_ref().and_then(|sci| match sci.backend {
+                Backend::Command{wait: true} => Some(true),
+                _ => None,
+            }),
+            editor_backend: sci.as_ref().and_then(|sci| match sci.backend {
+                Backend::Command{..} => None,
+                ref backend => Some(backend.name().to_owned()),
+            }),
+            nvim_socket: sci.as_mut().and_then(|sci| match sci.backend {
+                Backend::Neovim(ref mut socket) => rpl(socket),
+                _ => None,
+            }),
+            editor_follow: sci.as_ref().and_then(|sci| if sci.follow { Some(true) } else { None }),
             depth: Some(rpl(&mut conf.depth)),
             max_size: Some(rpl(&mut conf.max_size)),
             selected: Some(rpl(&mut conf.selected)),
+            lenient: if conf.lenient { Some(true) } else { None },
+            lazy: if conf.lazy { Some(true) } else { None },
+            sort_by_duration: if conf.sort_by_duration { Some(true) } else { None },
+            aggregate: if conf.aggregate { Some(true) } else { None },
+            fold: if conf.fold.is_default() { None } else { Some(conf.fold.clone().into()) },
+            remap: sci.as_mut().and_then(|sci| {
+                if sci.remap.is_empty() { None } else { Some(rpl(&mut sci.remap)) }
+            }),
             actions: if conf.actions.is_empty() { None } else { Some(rpl(&mut conf.actions)) },
+            bookmarks: if conf.bookmarks.is_empty() { None } else {
+                Some(rpl(&mut conf.bookmarks))
+            },
+            view: Some(rpl(&mut conf.view)),
             only: if conf.only.is_empty() { None } else {
                 // Take conf's only (type: Vec<Regex>), map it into Vec<String>, and wrap in Some
                 Some(rpl(&mut conf.only).iter().map(|r| r.to_string()).collect())
@@ -0,0 +1,355 @@
+//! Calling context tree: calls made through the same path are merged into a single node
+//!
+//! A loop calling `foo` 50,000 times produces a single `foo` node with a count instead of 50,000
+//! sibling rows. The offsets of the individual calls are kept, so that a node can be expanded
+//! back into them by reading the file again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use cursive_tree_view::Placement;
+
+use crate::config::Configuration;
+use crate::data::{Context, Item, Row, TreeType};
+use crate::init::{skip_or_fail, ReadReport, PROGRESS_EVERY};
+use crate::threads;
+use crate::trace::{self, Event, ParseError, RecordId, TraceReader};
+
+struct Node {
+    record: RecordId,
+    count: usize,
+    /// Total duration of the calls that have one
+    duration: Option<u64>,
+    /// Offsets of the individual calls, in chronological order
+    calls: Vec<u64>,
+    /// In the order of their first call
+    children: Vec<usize>,
+    /// Maps records to the children
+    child_ids: HashMap<RecordId, usize>,
+}
+
+impl Node {
+    fn new(record: RecordId) -> Self {
+        Node{
+            record: record,
+            count: 0,
+            duration: None,
+            calls: Vec::new(),
+            children: Vec::new(),
+            child_ids: HashMap::new(),
+        }
+    }
+}
+
+/// A call being read
+struct Open {
+    /// The node in the context tree, or the row in the view when expanding a node
+    id: Option<usize>,
+    record: RecordId,
+    start: Option<u64>,
+    /// Total duration of the children closed so far
+    children_time: u64,
+}
+
+fn duration(start: Option<u64>, end: Option<u64>) -> Option<u64> {
+    match (start, end) {
+        (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+        _ => None,
+    }
+}
+
+/// Position on the stack of the call closed by a '-' line, if there is one. The calls above it
+/// never returned
+fn closed_by(stack: &[Open], record: Option<RecordId>) -> Option<usize> {
+    match record {
+        Some(record) => stack.iter().rposition(|open| open.record == record),
+        None => stack.len().checked_sub(1),
+    }
+}
+
+pub struct ContextTree {
+    file:   PathBuf,
+    reader: Box<dyn TraceReader>,
+    /// The root, without a record of its own, is at 0
+    nodes:  Vec<Node>,
+}
+
+impl ContextTree {
+    /// Read the whole file and merge its calls by their paths
+    pub fn build(
+        configuration: &Configuration,
+        file: &Path,
+        report: &mut ReadReport,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<Self, ParseError> {
+        let mut context = ContextTree{
+            file: file.to_path_buf(),
+            reader: trace::open(file)?,
+            nodes: vec![Node::new(0)],
+        };
+        let mut stack: Vec<Open> = Vec::new();
+        let mut last_timestamp = None;
+
+        while let Some(event) = context.reader.next_event() {
+            let line_no = context.reader.line_no();
+            let timestamp = context.reader.timestamp();
+            last_timestamp = timestamp.or(last_timestamp);
+            match event {
+                Ok(Event::Push(record)) => {
+                    let parent = stack.last().and_then(|open| open.id).unwrap_or(0);
+                    let node = context.child(parent, record);
+                    context.nodes[node].count += 1;
+                    context.nodes[node].calls.push(context.reader.offset());
+                    stack.push(Open{
+                        id: Some(node), record: record, start: timestamp, children_time: 0,
+                    });
+                },
+                Ok(Event::Pop(record)) => match closed_by(&stack, record) {
+                    Some(pos) => {
+                        while stack.len() > pos + 1 {
+                            context.close(&mut stack, timestamp);
+                            report.never_returned += 1;
+                        }
+                        context.close(&mut stack, timestamp);
+                    },
+                    None => {
+                        let error = match (record, stack.last()) {
+                            (Some(record), Some(top)) => ParseError::UnbalancedPop{
+                                line_no,
+                                record: context.reader.records()[record].to_string(),
+                                expected: context.reader.records()[top.record].to_string(),
+                            },
+                            _ => ParseError::EmptyStackPop{line_no},
+                        };
+                        skip_or_fail(configuration, report, error)?;
+                    },
+                },
+                Err(e) => skip_or_fail(configuration, report, e)?,
+            }
+            if line_no % PROGRESS_EVERY == 0 {
+                progress(line_no);
+            }
+        }
+
+        // The trace ended abruptly, e.g. because the process crashed
+        while !stack.is_empty() {
+            context.close(&mut stack, last_timestamp);
+            report.never_returned += 1;
+        }
+        Ok(context)
+    }
+
+    /// The child of `parent` for the record, added if it is not there yet
+    fn child(&mut self, parent: usize, record: RecordId) -> usize {
+        if let Some(child) = self.nodes[parent].child_ids.get(&record) {
+            return *child;
+        }
+        let child = self.nodes.len();
+        self.nodes.push(Node::new(record));
+        self.nodes[parent].children.push(child);
+        self.nodes[parent].child_ids.insert(record, child);
+        child
+    }
+
+    fn close(&mut self, stack: &mut Vec<Open>, end: Option<u64>) {
+        let open = stack.pop().unwrap();
+        if let (Some(node), Some(duration)) = (open.id, duration(open.start, end)) {
+            let node = &mut self.nodes[node];
+            node.duration = Some(node.duration.unwrap_or(0) + duration);
+        }
+    }
+
+    fn insert_nodes(
+        &self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        parent: usize,
+        root: Option<usize>,
+        depth: u16,
+    ) {
+        let mut previous = None;
+        for &node in &self.nodes[parent].children {
+            if tree.len() >= configuration.max_size {
+                return;
+            }
+            let item = Row::Context(Context{
+                record: self.reader.records()[self.nodes[node].record].clone(),
+                count: self.nodes[node].count,
+                duration: self.nodes[node].duration,
+                node: node,
+            });
+            let (placement, row) = match (root, previous) {
+                (Some(root), _) => (Placement::LastChild, root),
+                (None, Some(previous)) => (Placement::After, previous),
+                (None, None) => (Placement::LastChild, 0),
+            };
+            let row = match tree.insert_item(item, placement, row) {
+                Some(row) => row,
+                None => continue,
+            };
+            if depth > 1 {
+                self.insert_nodes(configuration, tree, node, Some(row), depth - 1);
+            }
+            previous = Some(row);
+        }
+    }
+
+    /// Insert the top `configuration.depth` levels of the context tree under the `root` row, or
+    /// at the top level of an empty tree if there is none
+    pub fn populate(
+        &self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        root: Option<usize>,
+    ) {
+        self.insert_nodes(configuration, tree, 0, root, configuration.depth);
+    }
+
+    /// Replace the children of the node at the given row with its individual calls, each with
+    /// `configuration.depth` levels of its subtree. Only possible for uncompressed files
+    pub fn expand_calls(
+        &mut self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        row: usize,
+    ) -> Result<(), ParseError> {
+        let node = match tree.borrow_item(row) {
+            Some(Row::Context(context)) => context.node,
+            _ => return Ok(()),
+        };
+        tree.remove_children(row);
+        for i in 0..self.nodes[node].calls.len() {
+            if tree.len() >= configuration.max_size {
+                break;
+            }
+            let offset = self.nodes[node].calls[i];
+            self.insert_call(configuration, tree, offset, row)?;
+        }
+        Ok(())
+    }
+
+    /// Read the call starting at the offset and insert it as the last child of the row
+    fn insert_call(
+        &mut self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        offset: u64,
+        parent: usize,
+    ) -> Result<(), ParseError> {
+        self.reader.seek(offset)?;
+        let mut stack: Vec<Open> = Vec::new();
+        // Problems have been reported when the tree was built
+        let mut report = ReadReport::new();
+        while let Some(event) = self.reader.next_event() {
+            let timestamp = self.reader.timestamp();
+            match event {
+                Ok(Event::Push(record)) => {
+                    let parent_row = match stack.last() {
+                        Some(open) => open.id,
+                        None => Some(parent),
+                    };
+                    let room = stack.len() < configuration.depth as usize
+                        && tree.len() < configuration.max_size;
+                    let row = match parent_row {
+                        Some(parent_row) if room => tree.insert_item(
+                            Row::Call(Item::from(self.reader.records()[record].clone())),
+                            Placement::LastChild,
+                            parent_row,
+                        ),
+                        _ => None,
+                    };
+                    stack.push(Open{id: row, record: record, start: timestamp, children_time: 0});
+                },
+                Ok(Event::Pop(record)) => {
+                    // Unbalanced lines have been skipped when the tree was built
+                    if let Some(pos) = closed_by(&stack, record) {
+                        while stack.len() > pos {
+                            let returned = stack.len() == pos + 1;
+                            close_row(tree, &mut stack, timestamp, returned);
+                        }
+                    }
+                },
+                Err(e) => skip_or_fail(configuration, &mut report, e)?,
+            }
+            if stack.is_empty() {
+                return Ok(());
+            }
+        }
+
+        // The trace ended before the call returned
+        while !stack.is_empty() {
+            close_row(tree, &mut stack, None, false);
+        }
+        Ok(())
+    }
+
+    /// The context tree, out of those of all the threads, that the item at the given row was
+    /// built from
+    pub fn owning<'a>(
+        trees: &'a mut [ContextTree],
+        tree: &TreeType,
+        row: usize,
+    ) -> Option<&'a mut ContextTree> {
+        match threads::thread_of(tree, row) {
+            Some(thread) => trees.iter_mut().find(|context| context.file == thread.file),
+            None => trees.first_mut(),
+        }
+    }
+}
+
+/// Close the call on top of the stack, filling in its row in the view
+fn close_row(tree: &mut TreeType, stack: &mut Vec<Open>, end: Option<u64>, returned: bool) {
+    let open = stack.pop().unwrap();
+    let inclusive = duration(open.start, end);
+    if let (Some(inclusive), Some(parent)) = (inclusive, stack.last_mut()) {
+        parent.children_time += inclusive;
+    }
+    let item = open.id.and_then(|row| tree.borrow_item_mut(row)).and_then(Row::item_mut);
+    if let Some(item) = item {
+        item.never_returned = !returned;
+        item.inclusive = inclusive;
+        item.exclusive = inclusive.map(|inclusive| inclusive.saturating_sub(open.children_time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cli;
+    use structopt::StructOpt;
+
+    #[test]
+    fn stray_pop_does_not_cut_the_expanded_call_short() {
+        let file = std::env::temp_dir().join(
+            format!("stacktraceflow-{}-stray.txt", std::process::id()));
+        let text = "+main @src/main.rs:1:1: 9:2\n\
+                    +f @src/lib.rs:1:1: 2:2\n\
+                    -stray @src/lib.rs:5:1: 6:2\n\
+                    +g @src/lib.rs:3:1: 4:2\n\
+                    -g @src/lib.rs:3:1: 4:2\n\
+                    -f @src/lib.rs:1:1: 2:2\n\
+                    +f @src/lib.rs:1:1: 2:2\n\
+                    -f @src/lib.rs:1:1: 2:2\n\
+                    -main @src/main.rs:1:1: 9:2\n";
+        std::fs::write(&file, text).unwrap();
+        let args = vec!["stacktraceflow", "-f", file.to_str().unwrap(), "--aggregate",
+                        "--lenient", "-N", "2"];
+        let configuration = Configuration::load(Cli::from_iter(args));
+        let mut report = ReadReport::new();
+        let context = ContextTree::build(&configuration, &file, &mut report, &mut |_| {});
+        let mut context = context.unwrap();
+        assert_eq!(report.skipped.len(), 1);
+
+        let mut tree = TreeType::new();
+        context.populate(&configuration, &mut tree, None);
+        assert_eq!(tree.len(), 2);
+        let result = context.expand_calls(&configuration, &mut tree, 1);
+        std::fs::remove_file(&file).unwrap();
+        result.unwrap();
+        let functions: Vec<String> = (2..tree.len()).map(|row| {
+            tree.borrow_item(row).and_then(Row::record).unwrap().function.clone()
+        }).collect();
+        assert_eq!(functions, vec!["f", "g", "f"]);
+        assert_eq!(tree.item_parent(3), Some(2));
+    }
+}
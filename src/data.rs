@@ -1,24 +1,140 @@
 //! Useful datatypes
 
 use serde::{Serialize, Deserialize};
+use regex::Regex;
 
 use cursive_tree_view;
 
+use crate::path::CallPath;
+use crate::threads::Thread;
+use crate::timing::format_duration;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "kind", content = "args")]
 pub enum Action {
     Recursive(Record),
     Delete(Record),
+    /// Remove the call at the path together with its children
+    RemoveAt(CallPath),
+    /// Delete the call at the path but keep its children
+    DeleteAt(CallPath),
+    /// Remove all the calls matching the pattern together with their children
+    RecursiveMatching(Pattern),
+    /// Delete all the calls matching the pattern but keep their children
+    DeleteMatching(Pattern),
+}
+
+impl Action {
+    /// Whether the action hides every call of the record, wherever it is made. Actions on a
+    /// path, or scoped to one with `within`, depend on the tree and are not covered
+    pub fn hides(&self, record: &Record) -> bool {
+        match self {
+            Action::Delete(r) | Action::Recursive(r) => r == record,
+            Action::DeleteMatching(p) | Action::RecursiveMatching(p) => {
+                p.within.is_none() && p.matches(record)
+            },
+            Action::DeleteAt(_) | Action::RemoveAt(_) => false,
+        }
+    }
+
+    /// Whether the children of the calls hidden by the action are hidden too
+    pub fn is_recursive(&self) -> bool {
+        match self {
+            Action::Recursive(_) | Action::RemoveAt(_) | Action::RecursiveMatching(_) => true,
+            Action::Delete(_) | Action::DeleteAt(_) | Action::DeleteMatching(_) => false,
+        }
+    }
+}
+
+/// A call pinned by the user, with a note
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Bookmark {
+    pub note: String,
+    pub path: CallPath,
+}
+
+/// Regexes saved as their source
+pub(crate) mod optional_regex {
+    use regex::Regex;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        regex: &Option<Regex>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        regex.as_ref().map(Regex::as_str).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Regex>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(source) => Regex::new(&source).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Calls matched by their function name and/or the path of their file. A pattern without
+/// either matches nothing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pattern {
+    #[serde(default, with = "optional_regex", skip_serializing_if = "Option::is_none")]
+    pub function: Option<Regex>,
+    #[serde(default, with = "optional_regex", skip_serializing_if = "Option::is_none")]
+    pub file: Option<Regex>,
+    /// Only the calls below this one match
+    pub within: Option<CallPath>,
+}
+
+impl Pattern {
+    pub fn matches(&self, record: &Record) -> bool {
+        if self.function.is_none() && self.file.is_none() {
+            return false;
+        }
+        self.function.as_ref().map_or(true, |regex| regex.is_match(&record.function))
+            && self.file.as_ref().map_or(true, |regex| regex.is_match(&record.file))
+    }
 }
 
 /// A record to be shown in the tree
-#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Record {
     pub function: String,
     pub file: String,
     pub line: usize,
+    /// Column where the function starts
+    #[serde(default)]
+    pub column: usize,
+    /// Line where the function ends
+    #[serde(default)]
+    pub last_line: usize,
+    /// Column where the function ends
+    #[serde(default)]
+    pub last_column: usize,
+}
+
+/// The span is determined by the function, file and line, and is missing from actions saved by
+/// older versions. Leave it out of the comparison so that those actions still apply
+impl PartialEq for Record {
+    fn eq(&self, other: &Record) -> bool {
+        self.function == other.function && self.file == other.file && self.line == other.line
+    }
+}
+
+impl Eq for Record {}
+
+impl std::hash::Hash for Record {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        // Consistent with PartialEq
+        self.function.hash(state);
+        self.file.hash(state);
+        self.line.hash(state);
+    }
 }
 
+/// The form the `only` patterns and searches are matched against, kept as it was before the
+/// span was recorded so that the saved patterns still match
 impl std::fmt::Display for Record {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -31,4 +147,173 @@ impl std::fmt::Display for Record {
     }
 }
 
-pub type TreeType = cursive_tree_view::TreeView<Record>;
+impl Record {
+    /// The record as shown in the tree, with the span of the function when it is known
+    pub fn spanned(&self) -> Spanned<'_> {
+        Spanned(self)
+    }
+}
+
+pub struct Spanned<'a>(&'a Record);
+
+impl std::fmt::Display for Spanned<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let record = self.0;
+        // Older traces and saved actions have no span
+        if record.last_line == 0 {
+            return write!(f, "{}", record);
+        }
+        write!(
+            f,
+            "{file}:{line}:{column}-{last_line}:{last_column}    [{function}]",
+            file=&record.file,
+            line=&record.line,
+            column=&record.column,
+            last_line=&record.last_line,
+            last_column=&record.last_column,
+            function=&record.function,
+        )
+    }
+}
+
+/// A call shown in the tree view
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub record: Record,
+    /// The call was still on the stack when the trace ended, or its '-' line was missing
+    pub never_returned: bool,
+    /// Number of the call in the trace of its thread, counting the calls in the order they were
+    /// made, as in `TraceIndex::calls`. None for rows not read call by call, e.g. aggregated
+    pub call: Option<usize>,
+    /// Time spent in the call, in nanoseconds, if the trace has timestamps
+    pub inclusive: Option<u64>,
+    /// Time spent in the call but not in its children, in nanoseconds
+    pub exclusive: Option<u64>,
+    /// Number of consecutive identical calls folded into this item. The children are those of
+    /// the first one
+    pub repeated: usize,
+    /// Depth of the deepest chain of recursive calls folded into this item
+    pub recursion: usize,
+}
+
+impl From<Record> for Item {
+    fn from(record: Record) -> Self {
+        Item{
+            record: record,
+            never_returned: false,
+            call: None,
+            inclusive: None,
+            exclusive: None,
+            repeated: 1,
+            recursion: 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Item {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.repeated > 1 {
+            write!(f, "×{} ", self.repeated)?;
+        }
+        if let (Some(inclusive), Some(exclusive)) = (self.inclusive, self.exclusive) {
+            write!(
+                f, "[{} total, {} self]    ",
+                format_duration(inclusive), format_duration(exclusive),
+            )?;
+        }
+        if self.never_returned {
+            write!(f, "(never returned) ")?;
+        }
+        if self.recursion > 1 {
+            write!(f, "(recursion depth {}) ", self.recursion)?;
+        }
+        write!(f, "{}", self.record.spanned())
+    }
+}
+
+/// A function in the callers view, reached through the path of its ancestors in the view
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub record: Record,
+    /// Number of calls made through this path
+    pub count: usize,
+}
+
+impl std::fmt::Display for Caller {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{} calls]    {}", self.count, self.record.spanned())
+    }
+}
+
+/// Calls made through the same path, merged in the calling context tree
+#[derive(Clone, Debug)]
+pub struct Context {
+    pub record: Record,
+    pub count: usize,
+    /// Total time spent in the calls, in nanoseconds, if the trace has timestamps
+    pub duration: Option<u64>,
+    /// Node of the calling context tree
+    pub node: usize,
+}
+
+impl std::fmt::Display for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.duration {
+            Some(duration) => write!(
+                f, "[{} calls, {} total]    ", self.count, format_duration(duration))?,
+            None => write!(f, "[{} calls]    ", self.count)?,
+        }
+        write!(f, "{}", self.record.spanned())
+    }
+}
+
+/// A row of the tree view
+#[derive(Clone, Debug)]
+pub enum Row {
+    /// Top-level node holding the calls of one thread, when several threads are opened together
+    Thread(Thread),
+    Call(Item),
+    /// Row of the callers view
+    Caller(Caller),
+    /// Row of the calling context tree, when calls are aggregated
+    Context(Context),
+}
+
+impl Row {
+    /// The function shown in the row, unless it is a thread header
+    pub fn record(&self) -> Option<&Record> {
+        match self {
+            Row::Call(item) => Some(&item.record),
+            Row::Caller(caller) => Some(&caller.record),
+            Row::Context(context) => Some(&context.record),
+            Row::Thread(_) => None,
+        }
+    }
+
+    pub fn item(&self) -> Option<&Item> {
+        match self {
+            Row::Call(item) => Some(item),
+            Row::Thread(_) | Row::Caller(_) | Row::Context(_) => None,
+        }
+    }
+
+    pub fn item_mut(&mut self) -> Option<&mut Item> {
+        match self {
+            Row::Call(item) => Some(item),
+            Row::Thread(_) | Row::Caller(_) | Row::Context(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Row {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Row::Thread(thread) => write!(f, "{}", thread),
+            Row::Call(item) => write!(f, "{}", item),
+            Row::Caller(caller) => write!(f, "{}", caller),
+            Row::Context(context) => write!(f, "{}", context),
+        }
+    }
+}
+
+pub type TreeType = cursive_tree_view::TreeView<Row>;
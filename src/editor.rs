@@ -0,0 +1,312 @@
+//! Opening the source of a call in an external editor
+//!
+//! The editor command is a template split into words like a shell would: words are separated by
+//! whitespace, single quotes keep everything between them but placeholders as it is, double
+//! quotes keep whitespace but still allow escaping with a backslash, and a backslash outside of
+//! quotes escapes the next character. Pipes, redirections and other shell syntax are not
+//! interpreted; use `sh -c '...'` for those. Placeholders are replaced after the splitting, so a
+//! file name with spaces stays a single word:
+//!
+//! * `%F` - file name after remapping, relative to the source directory unless remapped to an
+//!   absolute path
+//! * `%L` - line where the function starts
+//! * `%C` - column where the function starts
+//! * `%E` - line where the function ends
+//! * `%f` - function name
+//! * `%D` - source directory
+//! * `%%` - a literal `%`
+//!
+//! The other backends do not start a new editor for every call, but reuse the one running
+//! already: Neovim is told through its msgpack-RPC socket, Emacs through emacsclient and Visual
+//! Studio Code through its `code` command, which passes the file on to the open window.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use rmpv::Value;
+
+use crate::config::{Backend, SourceCodeInfo};
+use crate::data::Record;
+
+/// Piece of a word of the template
+enum Part {
+    Text(String),
+    Placeholder(char),
+}
+
+type Word = Vec<Part>;
+
+fn push_char(word: &mut Word, c: char) {
+    match word.last_mut() {
+        Some(Part::Text(text)) => text.push(c),
+        _ => word.push(Part::Text(c.to_string())),
+    }
+}
+
+/// Split the template into words, keeping the placeholders unexpanded
+fn split(template: &str) -> Result<Vec<Word>, String> {
+    let mut words = Vec::new();
+    // None between words
+    let mut word: Option<Word> = None;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(word.take());
+            },
+            '\'' => {
+                let word = word.get_or_insert_with(Vec::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('%') => placeholder(word, chars.next())?,
+                        Some(c) => push_char(word, c),
+                        None => return Err("Unterminated single quote".to_owned()),
+                    }
+                }
+                // An empty quoted word is still a word
+                if word.is_empty() {
+                    word.push(Part::Text(String::new()));
+                }
+            },
+            '"' => {
+                let word = word.get_or_insert_with(Vec::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => push_char(word, c),
+                            Some(c) => {
+                                push_char(word, '\\');
+                                push_char(word, c);
+                            },
+                            None => return Err("Unterminated double quote".to_owned()),
+                        },
+                        Some('%') => placeholder(word, chars.next())?,
+                        Some(c) => push_char(word, c),
+                        None => return Err("Unterminated double quote".to_owned()),
+                    }
+                }
+                if word.is_empty() {
+                    word.push(Part::Text(String::new()));
+                }
+            },
+            '\\' => match chars.next() {
+                Some(c) => push_char(word.get_or_insert_with(Vec::new), c),
+                None => return Err("Trailing backslash".to_owned()),
+            },
+            '%' => placeholder(word.get_or_insert_with(Vec::new), chars.next())?,
+            c => push_char(word.get_or_insert_with(Vec::new), c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn placeholder(word: &mut Word, c: Option<char>) -> Result<(), String> {
+    match c {
+        Some('%') => push_char(word, '%'),
+        Some(c) if "FLCEfD".contains(c) => word.push(Part::Placeholder(c)),
+        Some(c) => return Err(format!("Unknown placeholder %{}", c)),
+        None => return Err("Trailing %".to_owned()),
+    }
+    Ok(())
+}
+
+/// The program and its arguments for opening the record
+fn command(template: &str, sci: &SourceCodeInfo, record: &Record) -> Result<Vec<String>, String> {
+    let words = split(template)?;
+    if words.is_empty() {
+        return Err("The editor command is empty".to_owned());
+    }
+    Ok(words.into_iter().map(|word| word.into_iter().map(|part| match part {
+        Part::Text(text) => text,
+        Part::Placeholder('F') => sci.file(&record.file),
+        Part::Placeholder('L') => record.line.to_string(),
+        Part::Placeholder('C') => record.column.to_string(),
+        Part::Placeholder('E') => record.last_line.to_string(),
+        Part::Placeholder('f') => record.function.clone(),
+        Part::Placeholder('D') => sci.dir.to_string_lossy().into_owned(),
+        Part::Placeholder(c) => unreachable!("Unknown placeholder %{}", c),
+    }).collect()).collect())
+}
+
+/// Start the program in the source directory. Unless the tree waits for it, it is kept away
+/// from the terminal, which the tree is drawn on
+fn spawn(command: &[String], dir: &Path, wait: bool) -> Result<Child, String> {
+    let mut process = Command::new(&command[0]);
+    process.current_dir(dir).args(&command[1..]);
+    if !wait {
+        process.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    process.spawn().map_err(|e| format!("Failed to run {}: {}", command[0], e))
+}
+
+/// Open the record in the editor. Returns the process started for it, if any, which has not
+/// necessarily exited yet
+pub fn open(sci: &SourceCodeInfo, record: &Record) -> Result<Option<Child>, String> {
+    let position = format!("{}:{}", record.line, std::cmp::max(record.column, 1));
+    let command = match sci.backend {
+        Backend::Command{wait} => match sci.editor {
+            Some(ref template) => {
+                return spawn(&command(template, sci, record)?, &sci.dir, wait).map(Some);
+            },
+            None => return Err("The editor command is not set".to_owned()),
+        },
+        Backend::Neovim(ref socket) => {
+            neovim(socket.as_ref().map(String::as_str), sci, record)?;
+            return Ok(None);
+        },
+        Backend::Emacs => vec![
+            "emacsclient".to_owned(),
+            "--no-wait".to_owned(),
+            format!("+{}", position),
+            sci.file(&record.file),
+        ],
+        Backend::VsCode => vec![
+            "code".to_owned(),
+            "--reuse-window".to_owned(),
+            "--goto".to_owned(),
+            format!("{}:{}", sci.file(&record.file), position),
+        ],
+    };
+    spawn(&command, &sci.dir, false).map(Some)
+}
+
+/// Wait for the editor to exit, turning a failure into a message
+pub fn wait(mut child: Child) -> Result<(), String> {
+    let status = child.wait().map_err(|e| e.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("The editor failed: {}", status))
+    }
+}
+
+/// How long to wait for Neovim to answer, so that a busy editor does not freeze the tree
+const NEOVIM_TIMEOUT: Duration = Duration::from_secs(2);
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+#[cfg(unix)]
+fn connect_local(path: &str) -> std::io::Result<Box<dyn Stream>> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(NEOVIM_TIMEOUT))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect_local(_path: &str) -> std::io::Result<Box<dyn Stream>> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "only TCP addresses are supported here"))
+}
+
+/// Connect to the socket, a host:port address or a path
+fn connect(address: &str) -> Result<Box<dyn Stream>, String> {
+    let result = match address.parse::<std::net::SocketAddr>() {
+        Ok(address) => std::net::TcpStream::connect_timeout(&address, NEOVIM_TIMEOUT)
+            .and_then(|stream| {
+                stream.set_read_timeout(Some(NEOVIM_TIMEOUT))?;
+                Ok(Box::new(stream) as Box<dyn Stream>)
+            }),
+        Err(_) => connect_local(address),
+    };
+    result.map_err(|e| format!("Cannot connect to Neovim at {}: {}", address, e))
+}
+
+/// Call an API function of Neovim and wait for its result
+fn request(
+    mut stream: &mut dyn Stream,
+    id: u32,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, String> {
+    let message = Value::Array(vec![0.into(), id.into(), method.into(), Value::Array(params)]);
+    rmpv::encode::write_value(&mut stream, &message)
+        .map_err(|e| format!("Cannot send to Neovim: {}", e))?;
+    loop {
+        let reply = rmpv::decode::read_value(&mut stream)
+            .map_err(|e| format!("No answer from Neovim: {}", e))?;
+        // Notifications and requests from Neovim are not for us
+        let reply = match reply.as_array() {
+            Some(reply) if reply.len() == 4 && reply[0].as_u64() == Some(1)
+                && reply[1].as_u64() == Some(id as u64) => reply.clone(),
+            _ => continue,
+        };
+        if reply[2].is_nil() {
+            return Ok(reply[3].clone());
+        }
+        // Errors come as [type, message]
+        let error = reply[2].as_array().and_then(|error| error.get(1)).and_then(Value::as_str)
+            .map(str::to_owned).unwrap_or_else(|| reply[2].to_string());
+        return Err(format!("Neovim failed to open the call: {}", error));
+    }
+}
+
+/// Open the record in the window of Neovim that has the focus
+fn neovim(socket: Option<&str>, sci: &SourceCodeInfo, record: &Record) -> Result<(), String> {
+    let address = match socket {
+        Some(socket) => socket.to_owned(),
+        None => std::env::var("NVIM").or_else(|_| std::env::var("NVIM_LISTEN_ADDRESS"))
+            .map_err(|_| "The Neovim socket is not set and $NVIM is empty".to_owned())?,
+    };
+    let mut stream = connect(&address)?;
+    let stream = stream.as_mut();
+    // Neovim has its own working directory
+    let path = sci.path(&record.file);
+    let path = std::fs::canonicalize(&path).unwrap_or(path);
+    let escaped = request(stream, 1, "nvim_call_function", vec![
+        "fnameescape".into(),
+        Value::Array(vec![path.to_string_lossy().as_ref().into()]),
+    ])?;
+    let escaped = escaped.as_str().ok_or_else(|| "Unexpected answer from Neovim".to_owned())?;
+    request(stream, 2, "nvim_command", vec![format!("edit {}", escaped).into()])?;
+    // Lines count from 1 and columns from 0 here
+    let cursor = vec![
+        std::cmp::max(record.line, 1).into(),
+        record.column.saturating_sub(1).into(),
+    ];
+    request(stream, 3, "nvim_win_set_cursor", vec![0.into(), Value::Array(cursor)])?;
+    request(stream, 4, "nvim_command", vec!["normal! zz".into()])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of the template, with the placeholders written as `{F}`
+    fn words(template: &str) -> Vec<String> {
+        split(template).unwrap().into_iter().map(|word| word.into_iter().map(|part| match part {
+            Part::Text(text) => text,
+            Part::Placeholder(c) => format!("{{{}}}", c),
+        }).collect()).collect()
+    }
+
+    #[test]
+    fn words_are_split_on_whitespace() {
+        assert_eq!(words("  vim  %F +%L "), vec!["vim", "{F}", "+{L}"]);
+        assert!(words("").is_empty());
+    }
+
+    #[test]
+    fn quotes_keep_whitespace_and_placeholders() {
+        assert_eq!(words("sh -c 'vim \"%F\" +%L'"), vec!["sh", "-c", "vim \"{F}\" +{L}"]);
+        assert_eq!(words(r#"a" b \" \\ \x"c"#), vec![r#"a b " \ \xc"#]);
+        assert_eq!(words("'' \"\""), vec!["", ""]);
+    }
+
+    #[test]
+    fn backslash_escapes_outside_quotes() {
+        assert_eq!(words(r"a\ b \%F 100%%"), vec!["a b", "%F", "100%"]);
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        for template in &["vim 'a", "vim \"a", "vim a\\", "vim %", "vim %x"] {
+            assert!(split(template).is_err(), "{}", template);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Writing the rows shown in the tree as a Graphviz DOT graph
+//!
+//! Only the rows the tree shows are written: the removed calls are gone, and the calls inside
+//! collapsed nodes are left out. The graph mode has a node per function and an edge per caller
+//! and callee, labeled with the number of calls. The tree mode has a node per row and keeps the
+//! order of the calls.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::data::{Record, Row, TreeType};
+use crate::stats;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    Graph,
+    Tree,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "graph" => Ok(Mode::Graph),
+            "tree"  => Ok(Mode::Tree),
+            _       => Err(format!("Unknown export mode '{}', expected 'graph' or 'tree'", s)),
+        }
+    }
+}
+
+/// Quote the text as a DOT string
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn label(record: &Record) -> String {
+    format!("{}\n{}:{}", record.function, record.file, record.line)
+}
+
+/// The closest ancestor of the row that is a call, skipping the thread nodes
+fn caller(tree: &TreeType, row: usize) -> Option<usize> {
+    let mut parent = tree.item_parent(row);
+    while let Some(row) = parent {
+        if tree.borrow_item(row).and_then(stats::calls).is_some() {
+            return Some(row);
+        }
+        parent = tree.item_parent(row);
+    }
+    None
+}
+
+fn graph(tree: &TreeType, out: &mut String) {
+    // Functions and caller-callee pairs, in the order they first appear
+    let mut functions: Vec<(&Record, usize)> = Vec::new();
+    let mut ids: HashMap<&Record, usize> = HashMap::new();
+    let mut edges: Vec<((usize, usize), usize)> = Vec::new();
+    let mut edge_ids: HashMap<(usize, usize), usize> = HashMap::new();
+    // Function of every row shown, if it is a call
+    let mut row_ids: Vec<Option<usize>> = Vec::with_capacity(tree.len());
+    for row in 0..tree.len() {
+        let (record, calls) = match tree.borrow_item(row) {
+            Some(item) => match (item.record(), stats::calls(item)) {
+                (Some(record), Some(calls)) => (record, calls),
+                _ => {
+                    row_ids.push(None);
+                    continue;
+                },
+            },
+            None => {
+                row_ids.push(None);
+                continue;
+            },
+        };
+        let id = *ids.entry(record).or_insert_with(|| {
+            functions.push((record, 0));
+            functions.len() - 1
+        });
+        functions[id].1 += calls;
+        row_ids.push(Some(id));
+        // Rows come after their parents
+        if let Some(caller) = caller(tree, row).and_then(|caller| row_ids[caller]) {
+            let edge = *edge_ids.entry((caller, id)).or_insert_with(|| {
+                edges.push(((caller, id), 0));
+                edges.len() - 1
+            });
+            edges[edge].1 += calls;
+        }
+    }
+
+    for (id, (record, calls)) in functions.iter().enumerate() {
+        let text = format!("{}\n{} calls", label(record), calls);
+        let _ = writeln!(out, "    f{} [label={}];", id, quote(&text));
+    }
+    for ((caller, callee), calls) in edges {
+        let _ = writeln!(
+            out, "    f{} -> f{} [label=\"{}\", weight={}];", caller, callee, calls, calls);
+    }
+}
+
+fn calls_tree(tree: &TreeType, out: &mut String) {
+    let _ = writeln!(out, "    ordering=out;");
+    for row in 0..tree.len() {
+        let text = match tree.borrow_item(row) {
+            Some(Row::Thread(thread)) => {
+                let text = quote(&thread.to_string());
+                let _ = writeln!(out, "    r{} [label={}, shape=folder];", row, text);
+                continue;
+            },
+            Some(item) => match (item.record(), stats::calls(item)) {
+                (Some(record), Some(calls)) if calls > 1 => {
+                    format!("{}\n×{}", label(record), calls)
+                },
+                (Some(record), _) => label(record),
+                (None, _) => continue,
+            },
+            None => continue,
+        };
+        let _ = writeln!(out, "    r{} [label={}];", row, quote(&text));
+        if let Some(parent) = tree.item_parent(row) {
+            let _ = writeln!(out, "    r{} -> r{};", parent, row);
+        }
+    }
+}
+
+/// The rows shown in the tree as a DOT graph
+pub fn dot(tree: &TreeType, mode: Mode) -> String {
+    let mut out = String::new();
+    out.push_str("digraph stacktraceflow {\n");
+    out.push_str("    node [shape=box, fontname=monospace];\n");
+    match mode {
+        Mode::Graph => graph(tree, &mut out),
+        Mode::Tree => calls_tree(tree, &mut out),
+    }
+    out.push_str("}\n");
+    out
+}
+
+pub fn write(tree: &TreeType, mode: Mode, path: &std::path::Path) -> std::io::Result<()> {
+    std::fs::write(path, dot(tree, mode))
+}
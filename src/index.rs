@@ -0,0 +1,151 @@
+//! Index of all the calls in a StackTraceFlow file, built in a single pass without keeping any
+//! records in memory
+
+use crate::config::Configuration;
+use crate::init::{skip_or_fail, ReadReport, PROGRESS_EVERY};
+use crate::trace::{Event, ParseError, RecordId, TraceReader};
+
+pub struct Call {
+    /// Offset of the event entering the function
+    pub start: u64,
+
+    /// Offset of the event closing the call, or of the end of the file if it never returned
+    pub end: u64,
+
+    /// Number of calls in the subtree, including this one
+    pub size: u64,
+
+    /// The call was still on the stack when the trace ended, or its '-' line was missing
+    pub never_returned: bool,
+
+    /// Time spent in the call, in nanoseconds, if the trace has timestamps
+    pub duration: Option<u64>,
+}
+
+/// All the calls in the order in which they were made. The children of call `i` are therefore
+/// `i + 1`, followed by each next sibling `j + calls[j].size`, up to `i + calls[i].size`
+pub struct TraceIndex {
+    pub calls: Vec<Call>,
+}
+
+impl TraceIndex {
+    /// Calls at the top of the stack, i.e., those without a parent
+    pub fn roots(&self) -> Vec<usize> {
+        self.siblings(0, self.calls.len())
+    }
+
+    pub fn children(&self, call: usize) -> Vec<usize> {
+        self.siblings(call + 1, call + self.calls[call].size as usize)
+    }
+
+    /// Time spent in the call but not in its children
+    pub fn exclusive(&self, call: usize) -> Option<u64> {
+        let children: u64 = self.children(call).iter().filter_map(
+            |&child| self.calls[child].duration).sum();
+        self.calls[call].duration.map(|duration| duration.saturating_sub(children))
+    }
+
+    fn siblings(&self, first: usize, end: usize) -> Vec<usize> {
+        let mut result = Vec::new();
+        let mut i = first;
+        while i < end {
+            result.push(i);
+            i += self.calls[i].size as usize;
+        }
+        result
+    }
+}
+
+/// Call on the stack while indexing
+struct Open {
+    call: usize,
+    record: RecordId,
+    timestamp: Option<u64>,
+}
+
+/// Close the call on top of the stack, which ended at `end` (an offset) and `end_time`
+fn close(
+    calls: &mut Vec<Call>,
+    stack: &mut Vec<Open>,
+    end: u64,
+    end_time: Option<u64>,
+    returned: bool,
+) {
+    let open = stack.pop().unwrap();
+    let size = calls.len() - open.call;
+    let call = &mut calls[open.call];
+    call.end = end;
+    call.size = size as u64;
+    call.never_returned = !returned;
+    call.duration = match (open.timestamp, end_time) {
+        (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+        _ => None,
+    };
+}
+
+/// Index all the calls of the trace. `progress` is called with the number of lines read every
+/// PROGRESS_EVERY lines
+pub fn build(
+    configuration: &Configuration,
+    reader: &mut dyn TraceReader,
+    report: &mut ReadReport,
+    progress: &mut dyn FnMut(usize),
+) -> Result<TraceIndex, ParseError> {
+    let mut calls: Vec<Call> = Vec::new();
+    let mut stack: Vec<Open> = Vec::new();
+    let mut last_timestamp = None;
+
+    while let Some(event) = reader.next_event() {
+        let line_no = reader.line_no();
+        let offset = reader.offset();
+        let timestamp = reader.timestamp();
+        last_timestamp = timestamp.or(last_timestamp);
+        match event {
+            Ok(Event::Push(record)) => {
+                stack.push(Open{call: calls.len(), record: record, timestamp: timestamp});
+                calls.push(Call{
+                    start: offset, end: 0, size: 0, never_returned: false, duration: None,
+                });
+            },
+            Ok(Event::Pop(record)) => {
+                let pos = match record {
+                    // Close the missing '-' lines up to the call this line belongs to
+                    Some(record) => stack.iter().rposition(|open| open.record == record),
+                    None => stack.len().checked_sub(1),
+                };
+                match pos {
+                    Some(pos) => {
+                        while stack.len() > pos + 1 {
+                            close(&mut calls, &mut stack, offset, timestamp, false);
+                            report.never_returned += 1;
+                        }
+                        close(&mut calls, &mut stack, offset, timestamp, true);
+                    },
+                    None => {
+                        let error = match (record, stack.last()) {
+                            (Some(record), Some(top)) => ParseError::UnbalancedPop{
+                                line_no,
+                                record: reader.records()[record].to_string(),
+                                expected: reader.records()[top.record].to_string(),
+                            },
+                            _ => ParseError::EmptyStackPop{line_no},
+                        };
+                        skip_or_fail(configuration, report, error)?;
+                    },
+                }
+            },
+            Err(e) => skip_or_fail(configuration, report, e)?,
+        }
+        if line_no % PROGRESS_EVERY == 0 {
+            progress(line_no);
+        }
+    }
+
+    // The trace ended abruptly, e.g. because the process crashed
+    let end = reader.offset();
+    while !stack.is_empty() {
+        close(&mut calls, &mut stack, end, last_timestamp, false);
+        report.never_returned += 1;
+    }
+    Ok(TraceIndex{calls: calls})
+}
@@ -1,92 +1,265 @@
-use std::io::BufRead;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::path::Path;
 use regex::Regex;
 
-use crate::data::{Record, TreeType};
-use crate::config::Configuration;
+use crate::data::{Item, Record, Row, TreeType};
+use crate::config::{Configuration, FoldRules};
+use crate::trace::{Event, ParseError, RecordId};
 
 struct Node {
-    /// Original line from the StackTraceFlow file, tripped of the initial '+' or '-' sign
-    orig_line: String,
+    /// Record of the function, as interned by the reader
+    record: RecordId,
+
+    /// Number of the call in the trace, see `Item::call`
+    call: usize,
 
     /// row (i.e., an id) of the associated line in the view. Set iff the node is being displayed
     view_row: Option<usize>,
 
     /// Did the current node match one of the 'only' patterns
     matched_an_only: bool,
+
+    /// Timestamp of the call, if the trace has them
+    start: Option<u64>,
+
+    /// Total duration of the children closed so far
+    children_time: u64,
+
+    /// Time spent in the function itself by the recursive calls folded into this one so far
+    folded_time: u64,
+
+    /// Hash of the function and of the shapes of the children closed so far
+    shape: DefaultHasher,
+
+    /// Run of identical calls the last child closed belongs to
+    last_run: Option<Run>,
+
+    /// Depth in the view. Recursive calls folded into an outer call count as one level
+    level: usize,
+
+    /// Position on the stack of the outer call of the same function that this recursive call
+    /// has been folded into. The node shares its row
+    folded_into: Option<usize>,
+
+    /// Record of a '+' line skipped in lenient mode, as written in the file. The node only
+    /// stands in for the call until its '-' line, and its children go to its parent
+    malformed: Option<String>,
+}
+
+impl Node {
+    fn new(
+        record: RecordId,
+        call: usize,
+        view_row: Option<usize>,
+        matched_an_only: bool,
+        level: usize,
+    ) -> Self {
+        let mut shape = DefaultHasher::new();
+        shape.write_usize(record);
+        Node{
+            record: record,
+            call: call,
+            view_row: view_row,
+            matched_an_only: matched_an_only,
+            start: None,
+            children_time: 0,
+            folded_time: 0,
+            shape: shape,
+            last_run: None,
+            level: level,
+            folded_into: None,
+            malformed: None,
+        }
+    }
+
+    /// Stand-in for a call whose '+' line could not be parsed. It shares the row and the level
+    /// of its parent, so it is not seen in the view
+    fn placeholder(stack: &[Node], text: &str) -> Self {
+        let parent = stack.last();
+        let mut node = Node::new(
+            RecordId::max_value(),
+            usize::max_value(),
+            parent.and_then(|parent| parent.view_row),
+            false,
+            level(stack),
+        );
+        node.malformed = Some(text.to_owned());
+        node
+    }
 }
 
-struct StackTraceFlowParser {
-    re:      Regex,
-    matches: HashMap<String, bool>,
+/// Depth in the view of the call on top of the stack
+fn level(stack: &[Node]) -> usize {
+    stack.last().map_or(0, |node| node.level)
+}
+
+/// If the function is already on the stack and recursion is folded, add the call as part of the
+/// outer call of that function instead of nesting it deeper. Returns whether it was folded
+fn push_recursive(
+    configuration: &Configuration,
+    tree: &mut TreeType,
+    stack: &mut Vec<Node>,
+    record: RecordId,
+    call: usize,
+) -> bool {
+    if !configuration.fold.recursion {
+        return false;
+    }
+    let outer = match stack.iter().position(|node| node.record == record) {
+        Some(outer) => outer,
+        None => return false,
+    };
+    let recursion = 1 + stack[outer..].iter().filter(|node| node.record == record).count();
+    let (view_row, matched, level) = {
+        let outer = &stack[outer];
+        (outer.view_row, outer.matched_an_only, outer.level)
+    };
+    let item = view_row.and_then(|row| tree.borrow_item_mut(row)).and_then(Row::item_mut);
+    if let Some(item) = item {
+        item.recursion = std::cmp::max(item.recursion, recursion);
+    }
+    let mut node = Node::new(record, call, view_row, matched, level);
+    node.folded_into = Some(outer);
+    stack.push(node);
+    true
 }
 
-impl StackTraceFlowParser {
-    fn new() -> Self {
-        StackTraceFlowParser{
-            re: Regex::new(r"(?x)
-                ^
-                (?P<function>[^@]+)
-                \s@
-                (?P<file>[^:]+)
-                :
-                (?P<line>\d+)
-                :\d+:  # column
-                \s\d+: # last line
-                \d+    # last column
-                $
-            ").expect("Failed to compile the parser's regex"),
-            matches: HashMap::new(),
+/// Consecutive sibling calls of the same function, with the same subtree shape
+struct Run {
+    record: RecordId,
+    shape: u64,
+    count: usize,
+    /// Rows of the calls. Once the run is long enough to be folded, only the first one is kept
+    rows: Vec<usize>,
+    inclusive: Option<u64>,
+    exclusive: Option<u64>,
+}
+
+fn add_durations(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        _ => a.or(b),
+    }
+}
+
+/// Folds runs of consecutive identical sibling calls into the row of the first one
+struct Folder<'a> {
+    rules: &'a FoldRules,
+    /// Whether the rules allow folding the record
+    foldable: HashMap<RecordId, bool>,
+    /// Run of the last call closed at the top level
+    top_level: Option<Run>,
+}
+
+impl<'a> Folder<'a> {
+    fn new(rules: &'a FoldRules) -> Self {
+        Folder{rules: rules, foldable: HashMap::new(), top_level: None}
+    }
+
+    fn is_foldable(&mut self, records: &[Record], record: RecordId) -> bool {
+        let rules = self.rules;
+        if rules.min_run == 0 {
+            return false;
         }
+        *self.foldable.entry(record).or_insert_with(|| {
+            let name = records[record].to_string();
+            (rules.functions.is_empty() || rules.functions.iter().any(|re| re.is_match(&name)))
+                && !rules.except.iter().any(|re| re.is_match(&name))
+        })
     }
 
-    fn parse(&self, line: &str) -> Record {
-        let cap = self.re.captures(line).expect(
-            &format!("Failed to capture based on regex from the line '{}'", line));
-        Record{
-            function: cap["function"].to_string(),
-            file: cap["file"].to_string(),
-            line: cap["line"].parse().expect(
-                &format!("Failed to parse line number in '{}'", line)),
+    /// A call has been closed. Add it to the run of its previous sibling if it is identical,
+    /// or start a new run
+    fn add(
+        &mut self,
+        records: &[Record],
+        tree: &mut TreeType,
+        run: &mut Option<Run>,
+        closed: Run,
+    ) {
+        match run {
+            Some(run) if run.record == closed.record && run.shape == closed.shape => {
+                run.count += 1;
+                run.rows.extend(closed.rows);
+                run.inclusive = add_durations(run.inclusive, closed.inclusive);
+                run.exclusive = add_durations(run.exclusive, closed.exclusive);
+                if run.count < std::cmp::max(self.rules.min_run, 2) {
+                    return;
+                }
+                // The calls were closed last, so their rows are at the end of the tree
+                for row in run.rows.drain(1..).rev() {
+                    tree.remove_item(row);
+                }
+                let item = run.rows.first().and_then(|&row| tree.borrow_item_mut(row))
+                    .and_then(Row::item_mut);
+                if let Some(item) = item {
+                    item.repeated = run.count;
+                    item.inclusive = run.inclusive;
+                    item.exclusive = run.exclusive;
+                }
+                return;
+            },
+            _ => {},
         }
+        *run = if self.is_foldable(records, closed.record) { Some(closed) } else { None };
     }
 }
 
+/// Row showing the call, which is the `call`th one in the trace
+fn call_row(records: &[Record], record: RecordId, call: usize) -> Row {
+    let mut item = Item::from(records[record].clone());
+    item.call = Some(call);
+    Row::Call(item)
+}
+
+/// Row of the closest ancestor that can hold children in the view. Calls without one go under
+/// `root`, or at the top level if there is none
+fn parent_row(stack: &[Node], root: Option<usize>) -> usize {
+    let root = root.unwrap_or(0);
+    stack.last().map_or(root, |node| node.view_row.unwrap_or(root))
+}
+
 fn add_line_with_full_tree(
-    parser: &mut StackTraceFlowParser,
+    records: &[Record],
+    _matches: &mut HashMap<RecordId, bool>,
     configuration: &Configuration,
     tree: &mut TreeType,
     stack: &mut Vec<Node>,
-    line: &str,
+    root: Option<usize>,
+    record: RecordId,
+    call: usize,
 ) {
+    if push_recursive(configuration, tree, stack, record, call) {
+        return;
+    }
     let mut view_row: Option<usize> = None;
-    if stack.len() < configuration.depth as usize {
+    if level(stack) < configuration.depth as usize {
 
         if tree.len() < configuration.max_size {
             view_row = Some(tree.insert_item(
-                parser.parse(line),
+                call_row(records, record, call),
                 cursive_tree_view::Placement::LastChild,
-                stack.last().map_or(0, |node| node.view_row.unwrap_or(0)),
+                parent_row(stack, root),
             ).unwrap());
         }
     }
-    stack.push(Node{
-        orig_line: line.to_string(),
-        view_row: view_row,
-        matched_an_only: false,
-    });
+    let level = level(stack) + 1;
+    stack.push(Node::new(record, call, view_row, false, level));
 }
 
 fn matches_an_only(
-    parser: &mut StackTraceFlowParser,
-    line: &str,
+    records: &[Record],
+    matches: &mut HashMap<RecordId, bool>,
+    record: RecordId,
     onlys: &Vec<Regex>,
 ) -> bool {
-    match parser.matches.get(line) {
+    match matches.get(&record) {
         Some(b) => *b,
         None => {
-            let value = onlys.iter().any(|re| re.is_match(&parser.parse(line).to_string()));
-            parser.matches.insert(line.to_string(), value);
+            let value = onlys.iter().any(|re| re.is_match(&records[record].to_string()));
+            matches.insert(record, value);
             value
         }
     }
@@ -94,51 +267,64 @@ fn matches_an_only(
 
 /// We decided to add a node to a tree. Make sure that all its ancestors have been added first
 fn add_current_path(
-    parser: &mut StackTraceFlowParser,
+    records: &[Record],
     stack: &mut Vec<Node>,
+    root: Option<usize>,
     tree: &mut TreeType,
 ) {
-    let mut previous_row: usize = 0;
-    for i in stack.iter_mut() {
-        if let None = i.view_row {
-            i.view_row = tree.insert_item(
-                parser.parse(&i.orig_line),
+    let mut previous_row: usize = root.unwrap_or(0);
+    for i in 0..stack.len() {
+        if let Some(outer) = stack[i].folded_into {
+            // The outer call is earlier on the stack, so it has its row by now
+            stack[i].view_row = stack[outer].view_row;
+        }
+        if stack[i].malformed.is_some() {
+            stack[i].view_row = if i == 0 { None } else { stack[i - 1].view_row };
+            continue;
+        }
+        if let None = stack[i].view_row {
+            stack[i].view_row = tree.insert_item(
+                call_row(records, stack[i].record, stack[i].call),
                 cursive_tree_view::Placement::LastChild,
                 previous_row,
             );
         }
-        previous_row = i.view_row.unwrap();
+        previous_row = stack[i].view_row.unwrap();
     }
 }
 
 fn add_line_with_only(
-    parser: &mut StackTraceFlowParser,
+    records: &[Record],
+    matches: &mut HashMap<RecordId, bool>,
     configuration: &Configuration,
     tree: &mut TreeType,
     stack: &mut Vec<Node>,
-    line: &str,
+    root: Option<usize>,
+    record: RecordId,
+    call: usize,
 ) {
-    let matched = matches_an_only(parser, line, &configuration.only);
+    if push_recursive(configuration, tree, stack, record, call) {
+        return;
+    }
+    let matched = matches_an_only(records, matches, record, &configuration.only);
+    let level = level(stack) + 1;
     if tree.len() >= configuration.max_size {
-        stack.push(Node{
-            orig_line: line.to_string(),
-            view_row: None,
-            matched_an_only: matched,
-        });
+        stack.push(Node::new(record, call, None, matched, level));
         return;
     }
 
     let mut view_row = None;
     if matched {
         // The current entry matches one of the 'only' patterns
-        add_current_path(parser, stack, tree);
+        add_current_path(records, stack, root, tree);
         view_row = Some(tree.insert_item(
-            parser.parse(line),
+            call_row(records, record, call),
             cursive_tree_view::Placement::LastChild,
-            stack.last().as_ref().unwrap().view_row.unwrap(),
+            parent_row(stack, root),
         ).unwrap());
     } else {
-        let recent_ancestors = stack.iter().rev().take(configuration.depth as usize);
+        let depth = configuration.depth as usize;
+        let recent_ancestors = stack.iter().rev().take_while(|node| node.level + depth >= level);
         let mut recent_ancestor_matched = false;
         for ref item in recent_ancestors {
             if item.matched_an_only {
@@ -147,60 +333,304 @@ fn add_line_with_only(
             }
         }
         if recent_ancestor_matched {
-            add_current_path(parser, stack, tree);
+            add_current_path(records, stack, root, tree);
             view_row = Some(tree.insert_item(
-                parser.parse(line),
+                call_row(records, record, call),
                 cursive_tree_view::Placement::LastChild,
-                stack.last().as_ref().unwrap().view_row.unwrap(),
+                parent_row(stack, root),
             ).unwrap());
         }
     }
-    stack.push(Node{
-        orig_line: line.to_string(),
-        view_row: view_row,
-        matched_an_only: matched,
-    });
+    stack.push(Node::new(record, call, view_row, matched, level));
 }
 
-fn del_line(_configuration: &Configuration, stack: &mut Vec<Node>, line: &str, counter: usize) {
-    let topmost_line = &stack.last().expect(
-        "Read a '-' StackTraceFlow line with an empty stack"
-    ).orig_line;
-    if topmost_line != &line {
-        panic!(
-            "StackTraceFlow line '{}' does not match top of the stack '{}' in line {}",
-            line, topmost_line, counter,
-        );
+/// Close the call on top of the stack, which ended at `end`. Calls closed without their own '-'
+/// line are marked in the view
+fn close(
+    records: &[Record],
+    tree: &mut TreeType,
+    stack: &mut Vec<Node>,
+    folder: &mut Folder,
+    end: Option<u64>,
+    returned: bool,
+    report: &mut ReadReport,
+) {
+    let node = stack.pop().unwrap();
+    if node.malformed.is_some() {
+        // Its line has been reported already
+        return;
+    }
+    let inclusive = match (node.start, end) {
+        (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+        _ => None,
+    };
+    let exclusive = inclusive.map(
+        |inclusive| inclusive.saturating_sub(node.children_time) + node.folded_time);
+    // Recursive calls folded into an outer one share its row, which the outer call fills in.
+    // Their time in the function itself is added to it, as their children are shown there too
+    if let Some(outer) = node.folded_into {
+        stack[outer].folded_time += exclusive.unwrap_or(0);
+    }
+    let view_row = if node.folded_into.is_some() { None } else { node.view_row };
+    let item = view_row.and_then(|row| tree.borrow_item_mut(row)).and_then(Row::item_mut);
+    if let Some(item) = item {
+        item.never_returned = !returned;
+        item.inclusive = inclusive;
+        item.exclusive = exclusive;
+    }
+    if !returned {
+        report.never_returned += 1;
+    }
+
+    let shape = node.shape.finish();
+    let closed = Run{
+        record: node.record,
+        shape: shape,
+        count: 1,
+        rows: view_row.into_iter().collect(),
+        inclusive: inclusive,
+        exclusive: exclusive,
+    };
+    match stack.last_mut() {
+        Some(parent) => {
+            parent.children_time += inclusive.unwrap_or(0);
+            parent.shape.write_u64(shape);
+            folder.add(records, tree, &mut parent.last_run, closed);
+        },
+        None => {
+            let mut run = folder.top_level.take();
+            folder.add(records, tree, &mut run, closed);
+            folder.top_level = run;
+        },
+    }
+}
+
+/// A function returned. Binary files do not say which one, so `record` is None for them
+fn del_line(
+    records: &[Record],
+    tree: &mut TreeType,
+    stack: &mut Vec<Node>,
+    folder: &mut Folder,
+    record: Option<RecordId>,
+    end: Option<u64>,
+    line_no: usize,
+    report: &mut ReadReport,
+) -> Result<(), ParseError> {
+    let top = stack.last().ok_or(ParseError::EmptyStackPop{line_no})?;
+    let topmost = top.record;
+    // A placeholder has no record of its own
+    let expected = match top.malformed {
+        Some(ref text) => text.clone(),
+        None => records[topmost].to_string(),
+    };
+    let record = match record {
+        Some(record) if record != topmost => record,
+        _ => {
+            close(records, tree, stack, folder, end, true, report);
+            return Ok(());
+        },
+    };
+
+    // The '-' lines of some calls went missing, e.g. because their thread was torn down
+    // mid-call. Close them up to the call this line belongs to
+    match stack.iter().rposition(|node| node.record == record) {
+        Some(pos) => {
+            while stack.len() > pos + 1 {
+                close(records, tree, stack, folder, end, false, report);
+            }
+            close(records, tree, stack, folder, end, true, report);
+            Ok(())
+        },
+        None => Err(ParseError::UnbalancedPop{
+            line_no,
+            record: records[record].to_string(),
+            expected: expected,
+        }),
     }
-    stack.pop();
 }
 
-pub fn read_stacktraceflow_file(configuration: &Configuration, tree: &mut TreeType) {
-    let mut parser = StackTraceFlowParser::new();
+/// Irregularities found in the StackTraceFlow file that did not stop it from being read
+pub struct ReadReport {
+    /// Malformed lines skipped in lenient mode
+    pub skipped: Vec<ParseError>,
+
+    /// Number of calls whose '-' line is missing, e.g. because the process crashed
+    pub never_returned: usize,
+
+    /// The tree of the thread filled its share of `max_size`
+    pub truncated: bool,
+}
+
+impl ReadReport {
+    pub fn new() -> Self {
+        ReadReport{skipped: Vec::new(), never_returned: 0, truncated: false}
+    }
+}
+
+/// How often readers of the whole file report their progress, in lines
+pub const PROGRESS_EVERY: usize = 100_000;
+
+/// In lenient mode, remember the problem and carry on. Otherwise, give up
+pub fn skip_or_fail(
+    configuration: &Configuration,
+    report: &mut ReadReport,
+    error: ParseError,
+) -> Result<(), ParseError> {
+    if configuration.lenient {
+        report.skipped.push(error);
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Collapse the rows into which runs of identical calls or recursive calls were folded, so that
+/// they show up as a single line until expanded
+pub fn collapse_folded(tree: &mut TreeType) {
+    // Backwards, so that collapsing a row does not move the ones still to be visited
+    for row in (0..tree.len()).rev() {
+        let item = tree.borrow_item(row).and_then(Row::item);
+        if item.map_or(false, |item| item.repeated > 1 || item.recursion > 1) {
+            tree.set_collapsed(row, true);
+        }
+    }
+}
+
+/// Whether the malformed line is the '-' line of the placeholder on top of the stack
+fn closes_placeholder(stack: &[Node], line: &str) -> bool {
+    let top = stack.last().and_then(|node| node.malformed.as_ref());
+    line.starts_with('-') && top.map_or(false, |text| text == &line[1..])
+}
+
+/// Read the StackTraceFlow file, either text or binary, into the tree. The calls go under the
+/// `root` row, or at the top level if there is none.
+///
+/// In lenient mode, malformed lines are skipped and returned in the report instead of aborting
+/// the whole read. Calls that never returned are closed automatically and marked in the tree.
+/// `progress` is called with the number of lines read every PROGRESS_EVERY lines.
+pub fn read_stacktraceflow_file(
+    configuration: &Configuration,
+    file: &Path,
+    tree: &mut TreeType,
+    root: Option<usize>,
+    progress: &mut dyn FnMut(usize),
+) -> Result<ReadReport, ParseError> {
+    let mut matches: HashMap<RecordId, bool> = HashMap::new();
     let mut stack: Vec<Node> = Vec::new();
+    let mut folder = Folder::new(&configuration.fold);
+    let mut report = ReadReport::new();
 
-    let file = std::fs::File::open(&configuration.file).expect("Could not open file");
-    let reader = std::io::BufReader::new(file);
-    let mut counter: usize = 1;
+    let mut reader = crate::trace::open(file)?;
     let add_fn = if configuration.only.is_empty() {
         add_line_with_full_tree
     } else {
         add_line_with_only
     };
 
-    println!("Number of lines read:");
-    for line in reader.lines() {
-        let line = line.unwrap();
-        if line.starts_with("+") {
-            add_fn(&mut parser, configuration, tree, &mut stack, &line[1..]);
-        } else if line.starts_with("-") {
-            del_line(configuration, &mut stack, &line[1..], counter);
-        } else {
-            panic!("Line '{}' starts with neither '+' nor '-' in line {}", line, counter);
-        }
-        counter += 1;
-        if counter%100000 == 0 {
-            println!("{}", counter);
+    let mut last_timestamp = None;
+    let mut calls = 0;
+    while let Some(event) = reader.next_event() {
+        let line_no = reader.line_no();
+        let timestamp = reader.timestamp();
+        last_timestamp = timestamp.or(last_timestamp);
+        let records = reader.records();
+        match event {
+            Ok(Event::Push(record)) => {
+                add_fn(
+                    records, &mut matches, configuration, tree, &mut stack, root, record, calls,
+                );
+                stack.last_mut().unwrap().start = timestamp;
+                calls += 1;
+            },
+            Ok(Event::Pop(record)) => {
+                let result = del_line(
+                    records, tree, &mut stack, &mut folder, record, timestamp, line_no,
+                    &mut report,
+                );
+                if let Err(e) = result {
+                    skip_or_fail(configuration, &mut report, e)?;
+                }
+            },
+            Err(e) => {
+                let line = e.malformed_line().map(str::to_owned);
+                match line {
+                    // The '-' line of a call whose '+' line was skipped, which has been reported
+                    Some(ref line) if closes_placeholder(&stack, line) => {
+                        stack.pop();
+                    },
+                    _ => {
+                        skip_or_fail(configuration, &mut report, e)?;
+                        // Keep a node on the stack so that the '-' line still balances
+                        if let Some(ref line) = line {
+                            if line.starts_with('+') {
+                                let node = Node::placeholder(&stack, &line[1..]);
+                                stack.push(node);
+                            }
+                        }
+                    },
+                }
+            },
+        }
+        if line_no % PROGRESS_EVERY == 0 {
+            progress(line_no);
+        }
+    }
+
+    // The trace ended abruptly, e.g. because the process crashed. Its calls last until the last
+    // event recorded
+    while !stack.is_empty() {
+        let records = reader.records();
+        close(records, tree, &mut stack, &mut folder, last_timestamp, false, &mut report);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cli;
+    use structopt::StructOpt;
+
+    fn read(name: &str, text: &str, lenient: bool) -> (TreeType, Result<ReadReport, ParseError>) {
+        let file = std::env::temp_dir().join(
+            format!("stacktraceflow-{}-{}.txt", std::process::id(), name));
+        std::fs::write(&file, text).unwrap();
+        let mut args = vec!["stacktraceflow", "-f", file.to_str().unwrap()];
+        if lenient {
+            args.push("--lenient");
+        }
+        let configuration = Configuration::load(Cli::from_iter(args));
+        let mut tree = TreeType::new();
+        let result = read_stacktraceflow_file(&configuration, &file, &mut tree, None, &mut |_| {});
+        std::fs::remove_file(&file).unwrap();
+        (tree, result)
+    }
+
+    #[test]
+    fn unmatched_pop_over_a_malformed_call_is_skipped() {
+        let text = "+main @src/main.rs:1:1: 9:2\n\
+                    +broken\n\
+                    -other @src/other.rs:2:1: 3:2\n\
+                    -broken\n\
+                    -main @src/main.rs:1:1: 9:2\n";
+        let (tree, result) = read("malformed", text, true);
+        let report = result.unwrap();
+        assert_eq!(report.skipped.len(), 2);
+        match report.skipped[1] {
+            ParseError::UnbalancedPop{ref expected, ..} => assert_eq!(expected, "broken"),
+            ref e => panic!("unexpected error {}", e),
+        }
+        assert_eq!(report.never_returned, 0);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn unmatched_pop_fails_unless_lenient() {
+        let text = "+main @src/main.rs:1:1: 9:2\n-other @src/other.rs:2:1: 3:2\n";
+        let (_, result) = read("unmatched", text, false);
+        match result {
+            Err(ParseError::UnbalancedPop{line_no, ..}) => assert_eq!(line_no, 2),
+            _ => panic!("the unmatched '-' line was not reported"),
         }
     }
 }
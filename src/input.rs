@@ -0,0 +1,135 @@
+//! Opening StackTraceFlow files, possibly compressed
+
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC:   &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Recognize the compression by the magic bytes at the beginning of the file, falling back to
+/// the file extension
+fn detect(path: &Path, head: &[u8]) -> Compression {
+    if head.starts_with(GZIP_MAGIC) {
+        return Compression::Gzip;
+    }
+    if head.starts_with(ZSTD_MAGIC) {
+        return Compression::Zstd;
+    }
+    if head.starts_with(XZ_MAGIC) {
+        return Compression::Xz;
+    }
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz")  => Compression::Gzip,
+        Some("zst") => Compression::Zstd,
+        Some("xz")  => Compression::Xz,
+        _           => Compression::None,
+    }
+}
+
+enum Inner {
+    Plain(BufReader<std::fs::File>),
+    Compressed(Box<dyn BufRead>),
+}
+
+/// Decompressed contents of a file, keeping track of the current offset
+pub struct Input {
+    inner:  Inner,
+    offset: u64,
+}
+
+impl Input {
+    fn reader(&mut self) -> &mut dyn BufRead {
+        match self.inner {
+            Inner::Plain(ref mut reader)      => reader,
+            Inner::Compressed(ref mut reader) => reader,
+        }
+    }
+
+    /// Offset of the next byte to be read, counted in the decompressed contents
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Jump to the given offset. Only possible if the file is not compressed
+    pub fn seek(&mut self, offset: u64) -> std::io::Result<()> {
+        match self.inner {
+            Inner::Plain(ref mut reader) => {
+                reader.seek(SeekFrom::Start(offset))?;
+                self.offset = offset;
+                Ok(())
+            },
+            Inner::Compressed(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "cannot jump around in a compressed file, decompress it first",
+            )),
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader().read(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader().fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader().consume(amt);
+        self.offset += amt as u64;
+    }
+}
+
+/// Open the file for reading, transparently decompressing it while streaming
+pub fn open(path: &Path) -> std::io::Result<Input> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let compression = detect(path, reader.fill_buf()?);
+    let inner = match compression {
+        Compression::None => Inner::Plain(reader),
+        Compression::Gzip => Inner::Compressed(
+            Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)))),
+        Compression::Zstd => Inner::Compressed(
+            Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(reader)?))),
+        Compression::Xz   => Inner::Compressed(
+            Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)))),
+    };
+    Ok(Input{inner: inner, offset: 0})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn concatenated_streams_are_read_to_the_end() {
+        let path = std::env::temp_dir().join(
+            format!("stacktraceflow-{}-concatenated.xz", std::process::id()));
+        let mut contents = Vec::new();
+        for part in &["+main @src/main.rs:1:1: 9:2\n", "-main @src/main.rs:1:1: 9:2\n"] {
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+            encoder.write_all(part.as_bytes()).unwrap();
+            contents.extend(encoder.finish().unwrap());
+        }
+        std::fs::write(&path, &contents).unwrap();
+        let mut text = String::new();
+        let result = open(&path).and_then(|mut input| input.read_to_string(&mut text));
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(text, "+main @src/main.rs:1:1: 9:2\n-main @src/main.rs:1:1: 9:2\n");
+    }
+}
@@ -0,0 +1,287 @@
+//! Tree whose nodes are read from the StackTraceFlow file only when they are expanded
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use cursive_tree_view::Placement;
+
+use crate::cache;
+use crate::config::Configuration;
+use crate::data::{Action, Item, Record, Row, TreeType};
+use crate::index::{self, TraceIndex};
+use crate::init::ReadReport;
+use crate::threads;
+use crate::walk;
+use crate::trace::{self, Event, ParseError, TraceReader};
+
+pub struct LazyTree {
+    file:   PathBuf,
+    index:  TraceIndex,
+    reader: Box<dyn TraceReader>,
+    /// Calls only some of whose children were loaded before the tree reached `max_size`
+    partial: HashSet<usize>,
+}
+
+/// Where to put the next item
+#[derive(Clone, Copy)]
+enum Position {
+    /// At the top level, after the given row (if any)
+    Root(Option<usize>),
+    /// As the last child of the given row
+    Child(usize),
+}
+
+impl LazyTree {
+    /// Index the file, or reuse the index cached next to it. Only uncompressed files can be
+    /// loaded lazily
+    pub fn open(
+        configuration: &Configuration,
+        file: &Path,
+        report: &mut ReadReport,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<Self, ParseError> {
+        let mut reader = trace::open(file)?;
+        let cached = cache::load(file).filter(
+            // Report the malformed lines again, unless they are to be skipped anyway
+            |cached| cached.skipped == 0 || configuration.lenient);
+        let index = match cached {
+            Some(cached) => {
+                reader.restore_records(cached.records);
+                report.never_returned = cached.index.calls.iter().filter(
+                    |call| call.never_returned).count();
+                cached.index
+            },
+            None => {
+                let index = index::build(configuration, &mut *reader, report, progress)?;
+                if let Err(e) = cache::save(file, &index, reader.records(), report.skipped.len()) {
+                    eprintln!(
+                        "Failed to write the index cache {}: {}",
+                        cache::path(file).to_string_lossy(), e,
+                    );
+                }
+                index
+            },
+        };
+        reader.seek(0)?;
+        Ok(LazyTree{
+            file: file.to_path_buf(),
+            index: index,
+            reader: reader,
+            partial: HashSet::new(),
+        })
+    }
+
+    fn record(&mut self, call: usize) -> Result<Record, ParseError> {
+        self.reader.seek(self.index.calls[call].start)?;
+        match self.reader.next_event() {
+            Some(Ok(Event::Push(record))) => Ok(self.reader.records()[record].clone()),
+            Some(Err(e)) => Err(e),
+            _ => Err(ParseError::BadEvent{
+                line_no: self.reader.line_no(),
+                reason: "the file changed after it was indexed",
+            }),
+        }
+    }
+
+    /// Insert the calls together with `depth` levels of their descendants, as long as the tree
+    /// is smaller than `max_size`. Deeper calls are left to be loaded on demand, and so are the
+    /// children of the calls cut short by `max_size`. The calls in `present` are in the tree
+    /// already. Returns None if the tree reached `max_size` before all the calls were inserted
+    fn insert_calls(
+        &mut self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        mut calls: Vec<usize>,
+        mut position: Position,
+        depth: u16,
+        present: &HashSet<usize>,
+    ) -> Result<Option<Position>, ParseError> {
+        if configuration.sort_by_duration {
+            // Longest first; calls without a duration keep their order after the others
+            calls.sort_by_key(|&call| std::cmp::Reverse(self.index.calls[call].duration));
+        }
+        for call in calls {
+            if present.contains(&call) {
+                continue;
+            }
+            if tree.len() >= configuration.max_size {
+                return Ok(None);
+            }
+            let record = self.record(call)?;
+            // The other actions are applied to the tree once it is loaded
+            let action = configuration.actions.iter().find(|act| act.hides(&record));
+            match action.map(Action::is_recursive) {
+                Some(true) => continue,
+                Some(false) => {
+                    // Children take the place of the deleted call
+                    let children = self.index.children(call);
+                    let inserted = self.insert_calls(
+                        configuration, tree, children, position, depth, present)?;
+                    match inserted {
+                        Some(next) => position = next,
+                        None => return Ok(None),
+                    }
+                    continue;
+                },
+                None => {},
+            }
+
+            let item = Row::Call(Item{
+                record: record,
+                never_returned: self.index.calls[call].never_returned,
+                call: Some(call),
+                inclusive: self.index.calls[call].duration,
+                exclusive: self.index.exclusive(call),
+                repeated: 1,
+                recursion: 1,
+            });
+            let has_children = self.index.calls[call].size > 1;
+            let descend = has_children && depth > 0 && tree.len() < configuration.max_size;
+            let (placement, row) = match position {
+                Position::Root(Some(row)) => (Placement::After, row),
+                Position::Root(None)      => (Placement::LastChild, 0),
+                Position::Child(row)      => (Placement::LastChild, row),
+            };
+            let new_row = if has_children && !descend {
+                tree.insert_container_item(item, placement, row)
+            } else {
+                tree.insert_item(item, placement, row)
+            };
+            let new_row = match new_row {
+                Some(new_row) => new_row,
+                None => continue,
+            };
+            if descend {
+                let children = self.index.children(call);
+                let position = Position::Child(new_row);
+                let depth = depth - 1;
+                let inserted = self.insert_calls(
+                    configuration, tree, children, position, depth, &HashSet::new())?;
+                if inserted.is_none() {
+                    self.partial.insert(call);
+                    return Ok(None);
+                }
+            } else if has_children {
+                tree.set_collapsed(new_row, true);
+            }
+            if let Position::Root(_) = position {
+                position = Position::Root(Some(new_row));
+            }
+        }
+        Ok(Some(position))
+    }
+
+    /// Insert the top `configuration.depth` levels of calls under the `root` row, or at the top
+    /// level of an empty tree if there is none
+    pub fn populate(
+        &mut self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        root: Option<usize>,
+    ) -> Result<(), ParseError> {
+        let roots = self.index.roots();
+        let depth = configuration.depth.saturating_sub(1);
+        let position = root.map_or(Position::Root(None), Position::Child);
+        self.insert_calls(configuration, tree, roots, position, depth, &HashSet::new())?;
+        Ok(())
+    }
+
+    /// Whether the expanded row has children to load: it has none yet, or only some of them
+    /// were loaded before the tree reached `max_size`
+    pub fn needs_loading(&self, tree: &TreeType, row: usize) -> bool {
+        match tree.borrow_item(row).and_then(Row::item).and_then(|item| item.call) {
+            Some(call) => {
+                self.partial.contains(&call) || walk::children(tree, Some(row)).is_empty()
+            },
+            None => false,
+        }
+    }
+
+    /// Load the children of the expanded item at the given row, or the rest of them, as long as
+    /// the tree is smaller than `max_size`. Returns false if some of them were left out because
+    /// of that limit
+    pub fn expand(
+        &mut self,
+        configuration: &Configuration,
+        tree: &mut TreeType,
+        row: usize,
+    ) -> Result<bool, ParseError> {
+        let call = match tree.borrow_item(row).and_then(Row::item).and_then(|item| item.call) {
+            Some(call) => call,
+            None => return Ok(true),
+        };
+        let children = self.index.children(call);
+        let position = Position::Child(row);
+        let present = walk::children(tree, Some(row)).into_iter()
+            .filter_map(|child| tree.borrow_item(child).and_then(Row::item)?.call)
+            .collect();
+        let inserted = self.insert_calls(configuration, tree, children, position, 0, &present)?;
+        if inserted.is_some() {
+            self.partial.remove(&call);
+        } else {
+            self.partial.insert(call);
+        }
+        Ok(inserted.is_some())
+    }
+
+    /// The lazy tree, out of those of all the threads, that the item at the given row was read
+    /// from
+    pub fn owning<'a>(
+        trees: &'a mut [LazyTree],
+        tree: &TreeType,
+        row: usize,
+    ) -> Option<&'a mut LazyTree> {
+        match threads::thread_of(tree, row) {
+            Some(thread) => trees.iter_mut().find(|lazy| lazy.file == thread.file),
+            None => trees.first_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cli;
+    use structopt::StructOpt;
+
+    fn functions(tree: &TreeType, rows: Vec<usize>) -> Vec<String> {
+        rows.into_iter().map(|row| {
+            tree.borrow_item(row).and_then(Row::record).unwrap().function.clone()
+        }).collect()
+    }
+
+    #[test]
+    fn expanding_again_loads_the_children_cut_off_by_max_size() {
+        let file = std::env::temp_dir().join(
+            format!("stacktraceflow-{}-partial.txt", std::process::id()));
+        let mut text = "+main @src/main.rs:1:1: 9:2\n".to_owned();
+        for f in &["a", "b", "c", "d"] {
+            text += &format!("+{} @src/lib.rs:1:1: 2:2\n-{} @src/lib.rs:1:1: 2:2\n", f, f);
+        }
+        text += "-main @src/main.rs:1:1: 9:2\n";
+        std::fs::write(&file, text).unwrap();
+        let args = vec!["stacktraceflow", "-f", file.to_str().unwrap(), "--lazy",
+                        "-N", "1", "-L", "3"];
+        let mut configuration = Configuration::load(Cli::from_iter(args));
+
+        let mut report = ReadReport::new();
+        let lazy = LazyTree::open(&configuration, &file, &mut report, &mut |_| {});
+        std::fs::remove_file(&file).unwrap();
+        let _ = std::fs::remove_file(cache::path(&file));
+        let mut lazy = lazy.unwrap();
+        let mut tree = TreeType::new();
+        lazy.populate(&configuration, &mut tree, None).unwrap();
+        assert_eq!(tree.len(), 1);
+
+        tree.expand_item(0);
+        assert!(lazy.needs_loading(&tree, 0));
+        assert!(!lazy.expand(&configuration, &mut tree, 0).unwrap());
+        assert_eq!(functions(&tree, walk::children(&tree, Some(0))), vec!["a", "b"]);
+        assert!(lazy.needs_loading(&tree, 0));
+
+        configuration.max_size = 10;
+        assert!(lazy.expand(&configuration, &mut tree, 0).unwrap());
+        assert_eq!(functions(&tree, walk::children(&tree, Some(0))), vec!["a", "b", "c", "d"]);
+        assert!(!lazy.needs_loading(&tree, 0));
+    }
+}
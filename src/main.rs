@@ -1,24 +1,299 @@
 use cursive;
 
 mod config;
-use config::Configuration;
+use config::{Backend, Cli, Command, Configuration, SourceCodeInfo};
 
 mod data;
-use data::{Action, TreeType};
+use data::{Action, Bookmark, Pattern, Record, Row, TreeType};
+
+mod input;
+
+mod trace;
+
+mod binary;
 
 mod init;
-use init::read_stacktraceflow_file;
+use init::{read_stacktraceflow_file, ReadReport};
+
+mod index;
+
+mod cache;
+
+mod lazy;
+use lazy::LazyTree;
+
+mod threads;
+
+mod timing;
+
+mod callers;
+
+mod context;
+use context::ContextTree;
+
+mod walk;
+
+mod stats;
+use stats::{Column, Stat};
+
+mod search;
+use search::{Search, TraceMatches};
+
+mod path;
+use path::CallPath;
+
+mod session;
+
+mod preview;
+use preview::Preview;
+
+mod editor;
+
+mod remap;
+
+mod export;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use cursive::views::{ScrollView, IdView, Dialog, TextView};
+use cursive::views::{ScrollView, IdView, Dialog, TextView, LinearLayout, SelectView, EditView};
+use cursive::views::{ListView, Checkbox, Panel};
+use cursive_tree_view::Placement;
 
 static mut CONFIGURATION: Option<Configuration> = None;
 
-fn perform_action(act: &Action, tree: &mut TreeType) {
+/// Readers of the files kept by the trees that are not read in full
+#[derive(Default)]
+struct Readers {
+    /// Lazily loaded trees, one per thread
+    lazy: Vec<LazyTree>,
+    /// Calling context trees, one per thread, when calls are aggregated
+    contexts: Vec<ContextTree>,
+}
+
+/// What the callbacks keep between them, as the user data of the Cursive root
+#[derive(Default)]
+struct State {
+    readers: Readers,
+    /// The view not shown at the moment: the callers while the calls are shown, and vice
+    /// versa. None until the callers view is opened for the first time
+    hidden_view: Option<TreeType>,
+    showing_callers: bool,
+    /// Functions listed in the flat profile, in the order they are shown
+    profile: Vec<Stat>,
+    /// The tree is being read again in the background, see `rebuild`
+    rebuilding: bool,
+    /// The last search, repeated with 'n' and 'N'
+    search: Option<Search>,
+    /// Source of the selected call, if the preview pane has been opened
+    preview: Option<Preview>,
+    /// The call opened in the editor last while following the selection
+    followed: Option<Record>,
+    /// Actions undone, the last one first to be redone
+    redo: Vec<Action>,
+}
+
+/// Number of the latest call to be opened while following the selection. The calls are opened
+/// in the background, and only if no other call has been selected in the meantime
+static FOLLOWING: AtomicUsize = AtomicUsize::new(0);
+
+/// How long the selection has to stay on a call before it is opened in the followed editor
+const FOLLOW_DELAY: Duration = Duration::from_millis(150);
+
+type ScrollType = ScrollView<IdView<TreeType>>;
+
+/// The state of the callbacks
+fn state(s: &mut cursive::Cursive) -> &mut State {
+    s.user_data::<State>().expect("The state is set before the callbacks are registered")
+}
+
+/// Call `f` with the tree view and the state of the callbacks
+fn with_tree<T>(
+    s: &mut cursive::Cursive,
+    f: impl FnOnce(&mut TreeType, &mut State) -> T,
+) -> Option<T> {
+    let mut tree = s.find_id::<TreeType>("tree")?;
+    Some(f(&mut tree, state(s)))
+}
+
+fn load(
+    configuration: &Configuration,
+    file: &Path,
+    tree: &mut TreeType,
+    readers: &mut Readers,
+    root: Option<usize>,
+    progress: &mut dyn FnMut(usize),
+) -> Result<ReadReport, trace::ParseError> {
+    if configuration.aggregate {
+        let mut report = ReadReport::new();
+        let context = ContextTree::build(configuration, file, &mut report, progress)?;
+        context.populate(configuration, tree, root);
+        readers.contexts.push(context);
+        return Ok(report);
+    }
+    if !configuration.lazy {
+        return read_stacktraceflow_file(configuration, file, tree, root, progress);
+    }
+    let mut report = ReadReport::new();
+    let mut lazy = LazyTree::open(configuration, file, &mut report, progress)?;
+    lazy.populate(configuration, tree, root)?;
+    readers.lazy.push(lazy);
+    Ok(report)
+}
+
+/// Read the file, or the files of all the threads, into an empty tree and apply the actions.
+/// `progress` is called with each file and the number of lines read from it so far.
+///
+/// Lazy and aggregated trees keep their readers in `readers`, which have to be empty first
+fn load_session(
+    configuration: &Configuration,
+    tree: &mut TreeType,
+    readers: &mut Readers,
+    progress: &mut dyn FnMut(&Path, usize),
+) -> Result<Vec<(Option<threads::Thread>, ReadReport)>, String> {
+    let failed = |file: &Path, e: trace::ParseError| {
+        let hint = match e {
+            // Skipping lines does not help if the file cannot be read
+            trace::ParseError::Io(_) => "",
+            _ => "\nUse --lenient to skip malformed lines",
+        };
+        format!("Failed to read StackTraceFlow file {}: {}{}", file.to_string_lossy(), e, hint)
+    };
+    let threads = threads::discover(&configuration.file).map_err(
+        |e| format!("Failed to open {}: {}", configuration.file.to_string_lossy(), e))?;
+
+    let mut reports = Vec::new();
+    match threads {
+        None => {
+            let file = &configuration.file;
+            let lines_read = &mut |n| progress(file, n);
+            let report = load(configuration, file, tree, readers, None, lines_read)
+                .map_err(|e| failed(file, e))?;
+            reports.push((None, report));
+        },
+        Some(threads) => {
+            let mut previous = None;
+            let count = threads.len();
+            for (i, thread) in threads.into_iter().enumerate() {
+                // Each thread goes after the previous one, at the top level
+                let item = Row::Thread(thread.clone());
+                let row = match previous {
+                    None => tree.insert_item(item, Placement::LastChild, 0),
+                    Some(row) => tree.insert_item(item, Placement::After, row),
+                };
+                // The threads share 'max_size', so that the first ones do not take it all. What
+                // a thread leaves unused goes to the ones after it
+                let mut share = configuration.clone();
+                let left = configuration.max_size.saturating_sub(tree.len());
+                share.max_size = tree.len() + left / (count - i);
+                let file = &thread.file;
+                let lines_read = &mut |n| progress(file, n);
+                let mut report = load(&share, file, tree, readers, row, lines_read)
+                    .map_err(|e| failed(file, e))?;
+                report.truncated = tree.len() >= share.max_size;
+                reports.push((Some(thread), report));
+                previous = row;
+            }
+        },
+    }
+
+    // Lazy trees are sorted as they are loaded
+    if configuration.sort_by_duration && !configuration.lazy {
+        timing::sort_by_duration(tree);
+    }
+    for act in &configuration.actions {
+        perform_action(&act, tree, configuration, &mut readers.lazy);
+    }
+    init::collapse_folded(tree);
+    Ok(reports)
+}
+
+/// Read the children of a lazily loaded row, unless they have been read already
+fn load_children(
+    configuration: &Configuration,
+    lazy: &mut [LazyTree],
+    tree: &mut TreeType,
+    row: usize,
+) -> Result<(), trace::ParseError> {
+    if !configuration.lazy {
+        return Ok(());
+    }
+    expand_lazily(configuration, lazy, tree, row).map(|_| ())
+}
+
+/// Read the children of a lazily loaded row and hide those matching the patterns scoped to a
+/// call above them. Returns whether the row had children to read
+fn expand_lazily(
+    configuration: &Configuration,
+    lazy: &mut [LazyTree],
+    tree: &mut TreeType,
+    row: usize,
+) -> Result<bool, trace::ParseError> {
+    let expanded = match LazyTree::owning(lazy, tree, row) {
+        Some(lazy) if lazy.needs_loading(tree, row) => lazy.expand(configuration, tree, row)?,
+        _ => return Ok(true),
+    };
+    // The unscoped patterns are applied as the calls are read
+    let path = CallPath::of(tree, row);
+    for act in &configuration.actions {
+        let (pattern, recursive) = match *act {
+            Action::DeleteMatching(ref pattern) => (pattern, false),
+            Action::RecursiveMatching(ref pattern) => (pattern, true),
+            _ => continue,
+        };
+        let scoped = match (&pattern.within, &path) {
+            (Some(within), Some(path)) => within.leads_to(path),
+            _ => false,
+        };
+        if scoped {
+            hide_matching(tree, pattern, Some(row), recursive);
+        }
+    }
+    Ok(expanded)
+}
+
+fn perform_action(
+    act: &Action,
+    tree: &mut TreeType,
+    configuration: &Configuration,
+    lazy: &mut [LazyTree],
+) {
+    // Calls that are not in the tree any more, e.g. because of new 'only' patterns or a smaller
+    // depth, are skipped rather than taken for another call of the same function
+    let mut find = |tree: &mut TreeType, path: &CallPath| {
+        path.find(tree, &mut |tree, row| load_children(configuration, lazy, tree, row))
+            .unwrap_or(None)
+    };
     match *act {
+        Action::DeleteAt(ref path) => {
+            if let Some(row) = find(tree, path) {
+                tree.extract_item(row);
+            }
+        },
+        Action::RemoveAt(ref path) => {
+            if let Some(row) = find(tree, path) {
+                tree.remove_item(row);
+            }
+        },
+        Action::DeleteMatching(ref pattern) | Action::RecursiveMatching(ref pattern) => {
+            let root = match pattern.within {
+                Some(ref path) => match find(tree, path) {
+                    Some(root) => Some(root),
+                    None => return,
+                },
+                None => None,
+            };
+            let recursive = match *act {
+                Action::RecursiveMatching(_) => true,
+                _ => false,
+            };
+            hide_matching(tree, pattern, root, recursive);
+        },
         Action::Delete(ref record) => {
             if let Some(mut row) = tree.row() {
                 for i in 0..tree.len() {
-                    while let Some(x) = tree.borrow_item(i) {
+                    while let Some(x) = tree.borrow_item(i).and_then(Row::record) {
                         if x != record {
                             break;
                         }
@@ -34,7 +309,7 @@ fn perform_action(act: &Action, tree: &mut TreeType) {
         Action::Recursive(ref record) => {
             if let Some(mut row) = tree.row() {
                 for i in 0..tree.len() {
-                    while let Some(x) = tree.borrow_item(i) {
+                    while let Some(x) = tree.borrow_item(i).and_then(Row::record) {
                         if x != record {
                             break;
                         }
@@ -52,56 +327,1009 @@ fn perform_action(act: &Action, tree: &mut TreeType) {
     }
 }
 
-fn add_action(act: Action) {
+/// Whether the row is in the subtree of `root`
+fn is_below(tree: &TreeType, row: usize, root: usize) -> bool {
+    let mut parent = tree.item_parent(row);
+    while let Some(row) = parent {
+        if row == root {
+            return true;
+        }
+        parent = tree.item_parent(row);
+    }
+    false
+}
+
+/// Delete the rows matching the pattern, or remove them together with their children if
+/// `recursive`. Only the rows below `root` are considered if there is one. Collapsed rows are
+/// expanded while they are searched and collapsed again afterwards
+fn hide_matching(tree: &mut TreeType, pattern: &Pattern, root: Option<usize>, recursive: bool) {
+    let mut selected = tree.row().unwrap_or(0);
+    // Rows expanded here, in ascending order. Changes are only made below them, so they keep
+    // their positions
+    let mut expanded = Vec::new();
+    let mut row = root.map_or(0, |root| root + 1);
+    while row < tree.len() && root.map_or(true, |root| is_below(tree, row, root)) {
+        let record = tree.borrow_item(row).and_then(Row::record);
+        let matches = record.map_or(false, |record| pattern.matches(record));
+        if !matches || !recursive {
+            // The hidden children of a deleted row take its place and are searched next
+            let len = tree.len();
+            tree.expand_item(row);
+            let shown = tree.len() - len;
+            if shown > 0 && !matches {
+                expanded.push(row);
+            }
+            if selected > row {
+                selected += shown;
+            }
+        }
+        if !matches {
+            row += 1;
+            continue;
+        }
+        let len = tree.len();
+        if recursive {
+            tree.remove_item(row);
+        } else {
+            tree.extract_item(row);
+        }
+        let removed = len - tree.len();
+        if removed == 0 {
+            row += 1;
+        } else if selected >= row + removed {
+            selected -= removed;
+        } else if selected > row {
+            selected = row;
+        }
+    }
+    for row in expanded.into_iter().rev() {
+        let len = tree.len();
+        tree.collapse_item(row);
+        let hidden = len - tree.len();
+        if selected > row + hidden {
+            selected -= hidden;
+        } else if selected > row {
+            selected = row;
+        }
+    }
+    if !tree.is_empty() {
+        tree.set_selected_row(std::cmp::min(selected, tree.len() - 1));
+    }
+}
+
+fn add_action(state: &mut State, act: Action) {
     unsafe {
         CONFIGURATION.as_mut().unwrap().actions.push(act);
     }
+    state.redo.clear();
+    // The callers view is built with the actions
+    state.hidden_view = None;
+}
+
+/// How many of the skipped lines are listed individually in the summary
+const SKIPPED_SHOWN: usize = 100;
+
+/// Summarize the reports of the files read, each labelled with its thread if there are several
+fn show_report(siv: &mut cursive::Cursive, reports: &[(Option<threads::Thread>, ReadReport)]) {
+    let mut text = String::new();
+    for (thread, report) in reports {
+        if report.skipped.is_empty() && report.never_returned == 0 && !report.truncated {
+            continue;
+        }
+        if let Some(thread) = thread {
+            text += &format!("{}:\n\n", thread);
+        }
+        if report.truncated {
+            text += "The tree of the thread reached its share of 'max_size', so some of its \
+                     calls may be left out.\n\n";
+        }
+        if report.never_returned > 0 {
+            text += &format!(
+                "{} calls never returned. They are marked with '(never returned)'.\n\n",
+                report.never_returned,
+            );
+        }
+        if !report.skipped.is_empty() {
+            text += &format!("Skipped {} malformed lines:\n\n", report.skipped.len());
+            for e in report.skipped.iter().take(SKIPPED_SHOWN) {
+                text += &format!("{}\n", e);
+            }
+            if report.skipped.len() > SKIPPED_SHOWN {
+                text += &format!("... and {} more\n", report.skipped.len() - SKIPPED_SHOWN);
+            }
+            text += "\n";
+        }
+    }
+    if text.is_empty() {
+        return;
+    }
+    siv.add_layer(
+        Dialog::around(ScrollView::new(TextView::new(text)))
+        .title("Incomplete StackTraceFlow file")
+        .button("Ok", |s| { s.pop_layer(); })
+    );
+}
+
+/// Scroll on the x axis to make the row visible
+fn scroll_to_row(s: &mut cursive::Cursive, row: usize) {
+    let x_position = s.call_on_id("tree", |tree: &mut TreeType| {
+        match (tree.first_col(row), tree.item_width(row)) {
+            (Some(offset), Some(width)) => Some((offset, width)),
+            _ => None
+        }
+    });
+    if let Some(Some((offset, width))) = x_position {
+        s.call_on_id("scroll", |s: &mut ScrollType| {
+            let viewport = s.content_viewport();
+            if viewport.left() > offset {
+                s.set_offset((offset, viewport.top()));
+            } else if viewport.right() < offset + width {
+                s.set_offset((offset + width - viewport.width(), viewport.top()));
+            }
+        });
+    }
+}
+
+/// The pane previewing the source of the selected call
+fn preview_pane() -> impl cursive::view::View {
+    use cursive::traits::{Boxable, Identifiable};
+    Panel::new(ScrollView::new(TextView::new("").with_id("source")).with_id("source_scroll"))
+        .title("Source")
+        .full_screen()
+}
+
+/// Show the source of the selected call in the preview pane, if it is open
+fn update_preview(s: &mut cursive::Cursive) {
+    let sci = match unsafe { CONFIGURATION.as_ref().unwrap().source_code_info.as_ref() } {
+        Some(sci) => sci.clone(),
+        None => return,
+    };
+    if s.call_on_id("source", |_: &mut TextView| ()).is_none() {
+        return;
+    }
+    let record = selected_record(s);
+    let text = state(s).preview.get_or_insert_with(Preview::new).show(&sci, record.as_ref());
+    if let Some(text) = text {
+        s.call_on_id("source", |view: &mut TextView| view.set_content(text));
+        s.call_on_id("source_scroll", |view: &mut ScrollView<IdView<TextView>>| {
+            view.scroll_to_top();
+        });
+    }
+}
+
+/// The call shown in the selected row
+fn selected_record(s: &mut cursive::Cursive) -> Option<Record> {
+    s.call_on_id("tree", |tree: &mut TreeType| {
+        tree.row().and_then(|row| tree.borrow_item(row)).and_then(Row::record).cloned()
+    }).unwrap_or(None)
+}
+
+/// Open the call in the editor. Unless told to wait for it, failures of the editor after it has
+/// started are reported later
+fn open_in_editor(
+    s: &mut cursive::Cursive,
+    sci: &SourceCodeInfo,
+    record: &Record,
+) -> Result<(), String> {
+    let child = match editor::open(sci, record)? {
+        Some(child) => child,
+        None => return Ok(()),
+    };
+    if let Backend::Command{wait: true} = sci.backend {
+        return editor::wait(child);
+    }
+    // Only a failure is worth reporting once the editor exits
+    let sink = s.cb_sink().clone();
+    std::thread::spawn(move || {
+        if let Err(e) = editor::wait(child) {
+            let _ = sink.send(Box::new(move |s: &mut cursive::Cursive| {
+                s.add_layer(Dialog::text(e).title("Fail").button("Ok", |s| {s.pop_layer();}));
+            }));
+        }
+    });
+    Ok(())
+}
+
+/// Open the selected call in the editor if it is followed and the call has changed. Following
+/// stops at the first failure, so that it is not reported for every row
+fn follow_in_editor(s: &mut cursive::Cursive) {
+    let sci = match unsafe { CONFIGURATION.as_ref().unwrap().source_code_info.as_ref() } {
+        Some(sci) if sci.follow => sci.clone(),
+        _ => return,
+    };
+    let record = selected_record(s);
+    let state = state(s);
+    if state.followed == record {
+        return;
+    }
+    state.followed = record.clone();
+    let record = match record {
+        Some(record) => record,
+        None => return,
+    };
+    // Holding an arrow key selects many calls in a row, and the editor may be slow to answer
+    let following = FOLLOWING.fetch_add(1, Ordering::SeqCst) + 1;
+    let sink = s.cb_sink().clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(FOLLOW_DELAY);
+        if FOLLOWING.load(Ordering::SeqCst) != following {
+            return;
+        }
+        let result = editor::open(&sci, &record).and_then(|child| match child {
+            Some(child) => editor::wait(child),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            let _ = sink.send(Box::new(move |s: &mut cursive::Cursive| stop_following(s, e)));
+        }
+    });
+}
+
+/// Stop following the selection after the editor failed to open a call
+fn stop_following(s: &mut cursive::Cursive, e: String) {
+    match unsafe { CONFIGURATION.as_mut().unwrap().source_code_info.as_mut() } {
+        Some(sci) if sci.follow => sci.follow = false,
+        // Stopped already
+        _ => return,
+    }
+    s.add_layer(Dialog::text(format!("{}\n\nStopped following the selection, press 'E' to \
+                                      start again", e))
+                .title("Fail").button("Ok", |s| {s.pop_layer();}));
+}
+
+/// Show the selected call in the preview pane and, if it is followed, in the editor
+fn follow_selection(s: &mut cursive::Cursive) {
+    update_preview(s);
+    follow_in_editor(s);
+}
+
+/// Follow the selected row: scroll to it, preview its source and open it in the editor
+fn on_select(s: &mut cursive::Cursive, row: usize) {
+    scroll_to_row(s, row);
+    follow_selection(s);
+}
+
+/// Register a global callback, after which the preview and the editor follow the selected row.
+/// The callbacks are ignored while the tree is being rebuilt, as the tree and the configuration
+/// are about to be replaced
+fn add_callback<E: Into<cursive::event::Event>>(
+    siv: &mut cursive::Cursive,
+    event: E,
+    callback: impl Fn(&mut cursive::Cursive) + 'static,
+) {
+    siv.add_global_callback(event, move |s| {
+        if state(s).rebuilding {
+            return;
+        }
+        callback(s);
+        follow_selection(s);
+    });
+}
+
+/// Select the next row showing `record`, counting from the selected one. Rows below the top
+/// level are skipped if `top_level_only` is set
+fn select_record(tree: &mut TreeType, record: &Record, top_level_only: bool) {
+    let start = tree.row().unwrap_or(0);
+    let len = tree.len();
+    let found = (0..len).map(|i| (start + i) % len).find(|&row| {
+        (!top_level_only || tree.item_parent(row).is_none())
+            && tree.borrow_item(row).and_then(Row::record) == Some(record)
+    });
+    if let Some(row) = found {
+        tree.set_selected_row(row);
+    }
+}
+
+/// Swap the calls and the callers views, building the latter when it is needed for the first time
+fn switch_view(s: &mut cursive::Cursive) -> Result<(), trace::ParseError> {
+    if state(s).hidden_view.is_none() {
+        let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+        let callers = callers::build(configuration)?;
+        let mut view = TreeType::new();
+        callers.populate(&mut view, configuration.max_size);
+        view.set_on_select(on_select);
+        state(s).hidden_view = Some(view);
+    }
+    with_tree(s, |tree, state| {
+        let record = tree.row().and_then(|row| tree.borrow_item(row))
+            .and_then(Row::record).cloned();
+        std::mem::swap(tree, state.hidden_view.as_mut().unwrap());
+        state.showing_callers = !state.showing_callers;
+        // Functions are at the top level of the callers view
+        if let Some(record) = record {
+            select_record(tree, &record, state.showing_callers);
+        }
+    });
+    Ok(())
+}
+
+/// Sort the flat profile by the column and show it again
+fn sort_profile(s: &mut cursive::Cursive, column: Column) {
+    let profile = &mut state(s).profile;
+    stats::sort(profile, column);
+    let items: Vec<String> = profile.iter().map(Stat::to_string).collect();
+    s.call_on_id("profile", |view: &mut SelectView<usize>| {
+        view.clear();
+        for (i, item) in items.into_iter().enumerate() {
+            view.add_item(item, i);
+        }
+    });
+}
+
+/// Open the flat profile of the calls view. Choosing a function jumps to its first call
+fn show_profile(s: &mut cursive::Cursive) {
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    let profile = s.call_on_id("tree", |tree: &mut TreeType| stats::build(tree));
+    state(s).profile = profile.unwrap_or(Vec::new());
+
+    use cursive::traits::{Boxable, Identifiable, Scrollable};
+    let list = SelectView::<usize>::new().on_submit(|s, &i: &usize| {
+        s.pop_layer();
+        with_tree(s, |tree, state| {
+            let record = &state.profile[i].record;
+            let row = walk::reveal(tree, None, true, |tree, row| {
+                tree.borrow_item(row).and_then(Row::record) == Some(record)
+            });
+            if let Some(row) = row {
+                tree.set_selected_row(row);
+            }
+        });
+        follow_selection(s);
+    });
+    let mut dialog = Dialog::around(
+        LinearLayout::vertical()
+            .child(TextView::new(Stat::header()))
+            .child(list.with_id("profile").scrollable())
+    ).title("Flat profile");
+    for &column in Column::ALL.iter() {
+        dialog = dialog.button(column.name(), move |s| sort_profile(s, column));
+    }
+    s.add_layer(dialog.button("Close", |s| { s.pop_layer(); }).full_screen());
+    sort_profile(s, Column::Calls);
+}
+
+/// Select the next (or the previous) call of the last search that is missing from the tree,
+/// reading the trace. Returns what to tell the user, if anything
+fn search_trace(
+    tree: &mut TreeType,
+    lazy: &mut [LazyTree],
+    search: &mut Search,
+    forward: bool,
+) -> Result<Option<String>, trace::ParseError> {
+    let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+    if search.trace.is_none() {
+        search.trace = Some(TraceMatches::find(configuration, &search.regex)?);
+    }
+    let matches = search.trace.as_ref().unwrap();
+    let total = matches.count();
+    if total == 0 {
+        return Ok(Some(format!("Nothing matches '{}'", search.regex)));
+    }
+    // Wrapping around at either end
+    let nth = match search.trace_match {
+        Some(nth) if forward => (nth + 1) % total,
+        Some(nth) => (nth + total - 1) % total,
+        None if forward => 0,
+        None => total - 1,
+    };
+    search.trace_match = Some(nth);
+    let found = matches.get(nth);
+
+    let load = &mut |tree: &mut TreeType, row: usize| {
+        load_children(configuration, lazy, tree, row)
+    };
+    let (row, shown) = search::closest(tree, &found, load)?;
+    if let Some(row) = row {
+        tree.set_selected_row(row);
+    }
+    if shown {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "Call {} of {} to {} is not in the tree because of the 'depth' or 'max_size' limits. \
+         The closest call shown is selected. It was called through:\n\n{}",
+        nth + 1, total, found.record(), found.describe(),
+    )))
+}
+
+/// Select the next (or the previous) match of the last search after `from`. Calls missing from
+/// the tree are searched for in the trace if none of the rows match
+fn search_from(s: &mut cursive::Cursive, from: Option<usize>, forward: bool) {
+    let result = with_tree(s, |tree, state| {
+        let search = match state.search.as_mut() {
+            Some(search) => search,
+            None => return Ok(Some("Start a search with '/' first".to_owned())),
+        };
+        if search.trace_match.is_none() && search::in_tree(tree, &search.regex, from, forward) {
+            return Ok(None);
+        }
+        if state.showing_callers {
+            return Ok(Some(format!("Nothing matches '{}'", search.regex)));
+        }
+        search_trace(tree, &mut state.readers.lazy, search, forward)
+    });
+    let message = match result {
+        Some(Ok(Some(message))) => message,
+        Some(Err(e)) => format!("Failed to search the trace: {}", e),
+        _ => return,
+    };
+    s.add_layer(Dialog::text(message).title("Search").button("Ok", |s| {s.pop_layer();}));
+}
+
+/// Prompt for a regex and select the rows matching it while it is typed
+fn start_search(s: &mut cursive::Cursive) {
+    let start = s.call_on_id("tree", |tree: &mut TreeType| tree.row()).unwrap_or(None);
+    // The selected row is the first candidate
+    let select_first = move |s: &mut cursive::Cursive, regex: &regex::Regex| {
+        s.call_on_id("tree", |tree: &mut TreeType| {
+            match start {
+                Some(start) if search::matches(regex, tree.borrow_item(start)) => {
+                    tree.set_selected_row(start);
+                    true
+                },
+                _ => search::in_tree(tree, regex, start, true),
+            }
+        }).unwrap_or(false)
+    };
+
+    let edit = EditView::new()
+        .on_edit(move |s, text, _| {
+            if let Ok(regex) = regex::Regex::new(text) {
+                select_first(s, &regex);
+                // The editor follows only once the search is submitted, not every keystroke
+                update_preview(s);
+            }
+        })
+        .on_submit(move |s, text| {
+            s.pop_layer();
+            match regex::Regex::new(text) {
+                Ok(regex) => {
+                    let found = select_first(s, &regex);
+                    state(s).search = Some(Search::new(regex));
+                    if !found {
+                        search_from(s, start, true);
+                    }
+                    follow_selection(s);
+                },
+                Err(e) => {
+                    s.add_layer(Dialog::text(format!("Invalid regex: {}", e))
+                                .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                },
+            }
+        });
+    s.add_layer(Dialog::around(edit).title("Search (regex)").button("Cancel", move |s| {
+        s.pop_layer();
+        if let Some(start) = start {
+            s.call_on_id("tree", |tree: &mut TreeType| tree.set_selected_row(start));
+        }
+        follow_selection(s);
+    }));
+}
+
+/// Read the tree again with a new configuration, in the background and showing the progress.
+/// The configuration is saved once it is done. Until then, the keys registered with
+/// `add_callback` are ignored, so that nothing changes the tree or the configuration in between
+fn rebuild(s: &mut cursive::Cursive, configuration: Configuration) {
+    if state(s).rebuilding {
+        return;
+    }
+    if configuration.lazy || configuration.aggregate {
+        // Those keep readers of the files, which only the UI thread uses
+        reload(s, move |current, _| {
+            *current = configuration;
+            Ok(())
+        });
+        save(s);
+        follow_selection(s);
+        return;
+    }
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+
+    use cursive::traits::Identifiable;
+    s.add_layer(Dialog::around(TextView::new("Reading the trace").with_id("progress"))
+                .title("Rebuilding the tree").with_id("rebuilding"));
+    state(s).rebuilding = true;
+    let sink = s.cb_sink().clone();
+    std::thread::spawn(move || {
+        let mut tree = TreeType::new();
+        let progress_sink = sink.clone();
+        let progress = &mut |file: &Path, lines: usize| {
+            let text = format!("{}: {} lines read", file.to_string_lossy(), lines);
+            // Nothing to do if the UI is gone
+            let _ = progress_sink.send(Box::new(move |s: &mut cursive::Cursive| {
+                s.call_on_id("progress", |view: &mut TextView| view.set_content(text));
+            }));
+        };
+        // Neither lazy nor aggregated, so there are no readers to keep
+        let result = load_session(&configuration, &mut tree, &mut Readers::default(), progress)
+            .map(|_| ());
+        // The tree view holds callbacks, which cannot be sent to the UI thread
+        let rows = walk::take(tree);
+        let _ = sink.send(Box::new(move |s: &mut cursive::Cursive| {
+            state(s).rebuilding = false;
+            // Other dialogs, e.g. the help, may have been opened on top of it
+            if let Some(layer) = s.screen_mut().find_layer_from_id("rebuilding") {
+                s.screen_mut().remove_layer(layer);
+            }
+            if let Err(e) = result {
+                s.add_layer(Dialog::text(e).title("Fail").button("Ok", |s| {s.pop_layer();}));
+                return;
+            }
+            let mut configuration = configuration;
+            let result = with_tree(s, |tree, state| {
+                let view = session::capture(unsafe { CONFIGURATION.as_ref().unwrap() }, tree);
+                configuration.selected = tree.row().unwrap_or(0);
+                let mut rebuilt = TreeType::new();
+                walk::fill(&mut rebuilt, rows);
+                rebuilt.set_on_select(on_select);
+                *tree = rebuilt;
+                // Built with the old depth
+                state.hidden_view = None;
+                restore_view(&configuration, &mut state.readers.lazy, tree, &view)
+            });
+            unsafe {
+                CONFIGURATION = Some(configuration);
+            }
+            save(s);
+            follow_selection(s);
+            if let Some(Err(e)) = result {
+                s.add_layer(Dialog::text(format!("Failed to restore the view: {}", e))
+                            .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            }
+        }));
+    });
+}
+
+/// Save the configuration together with the selected row and the collapsed nodes
+fn save(s: &mut cursive::Cursive) {
+    unsafe {
+        let configuration = CONFIGURATION.as_mut().expect(
+            "The CONFIGURATION object died before saving. Please report an issue"
+        );
+        let capture = |tree: &mut TreeType| {
+            (tree.row().unwrap_or(0), session::capture(configuration, tree))
+        };
+        // The calls view is hidden while the callers are shown
+        let state = with_tree(s, |tree, state| match state.hidden_view.as_mut() {
+            Some(calls) if state.showing_callers => capture(calls),
+            _ => capture(tree),
+        });
+        let (row, view) = state.unwrap_or_default();
+        configuration.view = view;
+        configuration.save(row);
+    }
+}
+
+/// Put the tree, just read, into the state of `view`. If the selected call is not found, e.g.
+/// because it was saved by an older version, the row `configuration.selected` is selected
+fn restore_view(
+    configuration: &Configuration,
+    lazy: &mut [LazyTree],
+    tree: &mut TreeType,
+    view: &session::ViewState,
+) -> Result<(), trace::ParseError> {
+    if !tree.is_empty() {
+        tree.set_selected_row(std::cmp::min(configuration.selected, tree.len() - 1));
+    }
+    session::restore(tree, view, &mut |tree, row| load_children(configuration, lazy, tree, row))
+}
+
+/// Add one more 'only' pattern to the dialog
+fn add_only_field(s: &mut cursive::Cursive, pattern: &str) {
+    use cursive::traits::Identifiable;
+    let mut n = 0;
+    while s.call_on_id(&format!("only{}", n), |_: &mut EditView| ()).is_some() {
+        n += 1;
+    }
+    let field = EditView::new().content(pattern).with_id(format!("only{}", n));
+    s.call_on_id("only", |list: &mut ListView| list.add_child("Regex", field));
+}
+
+/// Open the dialog to edit the 'only' patterns and the depth, and rebuild the tree with them
+fn edit_only(s: &mut cursive::Cursive) {
+    use cursive::traits::{Boxable, Identifiable};
+    let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+    let depth = EditView::new().content(configuration.depth.to_string()).with_id("depth");
+    s.add_layer(
+        Dialog::around(LinearLayout::vertical()
+            .child(ListView::new().child("Depth", depth))
+            .child(TextView::new("\nShow only the parents and children of the calls matching:"))
+            .child(ListView::new().with_id("only")))
+        .title("Only (leave a regex empty to remove it)")
+        .button("Add regex", |s| add_only_field(s, ""))
+        .button("Apply", |s| {
+            let mut configuration = unsafe { CONFIGURATION.as_ref().unwrap().clone() };
+            let depth = s.call_on_id("depth", |view: &mut EditView| view.get_content());
+            match depth.and_then(|depth| depth.trim().parse().ok()) {
+                Some(depth) => configuration.depth = depth,
+                None => {
+                    s.add_layer(Dialog::text("The depth has to be a number")
+                                .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                    return;
+                },
+            }
+            let mut only = Vec::new();
+            let mut n = 0;
+            while let Some(pattern) = s.call_on_id(
+                &format!("only{}", n), |view: &mut EditView| view.get_content()) {
+                n += 1;
+                if pattern.trim().is_empty() {
+                    continue;
+                }
+                match regex::Regex::new(&pattern) {
+                    Ok(regex) => only.push(regex),
+                    Err(e) => {
+                        s.add_layer(Dialog::text(format!("Invalid regex: {}", e))
+                                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                        return;
+                    },
+                }
+            }
+            if !only.is_empty() && (configuration.lazy || configuration.aggregate) {
+                s.add_layer(
+                    Dialog::text("'only' patterns cannot be used in lazy mode or with \
+                                  aggregated calls")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                return;
+            }
+            configuration.only = only;
+            s.pop_layer();
+            rebuild(s, configuration);
+        })
+        .button("Cancel", |s| { s.pop_layer(); })
+        .full_width()
+    );
+    for regex in &configuration.only {
+        add_only_field(s, regex.as_str());
+    }
+}
+
+/// Open the dialog to hide the calls matching regexes, and save that as an action
+fn hide_dialog(s: &mut cursive::Cursive) {
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    use cursive::traits::Identifiable;
+    let fields = ListView::new()
+        .child("Function regex", EditView::new().with_id("hide_function"))
+        .child("File path regex", EditView::new().with_id("hide_file"))
+        .child("Only below the selected call", Checkbox::new().with_id("hide_within"))
+        .child("Keep their children", Checkbox::new().with_id("hide_keep"));
+    s.add_layer(Dialog::around(fields).title("Hide calls")
+        .button("Hide", |s| {
+            let regex = |s: &mut cursive::Cursive, id: &str| {
+                let source = s.call_on_id(id, |view: &mut EditView| view.get_content())?;
+                if source.is_empty() { None } else { Some(regex::Regex::new(&source)) }
+            };
+            let (function, file) = match (regex(s, "hide_function"), regex(s, "hide_file")) {
+                (Some(Err(e)), _) | (_, Some(Err(e))) => {
+                    s.add_layer(Dialog::text(format!("Invalid regex: {}", e))
+                                .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                    return;
+                },
+                (None, None) => {
+                    s.add_layer(Dialog::text("Fill in a function or a file path regex")
+                                .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                    return;
+                },
+                (function, file) => (function.map(Result::unwrap), file.map(Result::unwrap)),
+            };
+            let checked = |s: &mut cursive::Cursive, id: &str| {
+                s.call_on_id(id, |view: &mut Checkbox| view.is_checked()).unwrap_or(false)
+            };
+            let within = checked(s, "hide_within");
+            let keep = checked(s, "hide_keep");
+            s.pop_layer();
+
+            with_tree(s, |tree, state| {
+                let within = match tree.row() {
+                    Some(row) if within => CallPath::of(tree, row),
+                    _ => None,
+                };
+                let pattern = Pattern{function: function, file: file, within: within};
+                let action = if keep {
+                    Action::DeleteMatching(pattern)
+                } else {
+                    Action::RecursiveMatching(pattern)
+                };
+                let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+                perform_action(&action, tree, configuration, &mut state.readers.lazy);
+                add_action(state, action);
+            });
+            follow_selection(s);
+        })
+        .button("Cancel", |s| { s.pop_layer(); }));
+}
+
+/// Add the note of a call to the bookmarks, or change it if the call is bookmarked already
+fn set_bookmark(path: &CallPath, note: &str) {
+    let bookmarks = unsafe { &mut CONFIGURATION.as_mut().unwrap().bookmarks };
+    match bookmarks.iter_mut().find(|bookmark| &bookmark.path == path) {
+        Some(bookmark) => bookmark.note = note.to_owned(),
+        None => bookmarks.push(Bookmark{note: note.to_owned(), path: path.clone()}),
+    }
+}
+
+/// Open the dialog to write the rows shown as a Graphviz DOT file
+fn export_dialog(s: &mut cursive::Cursive) {
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    use cursive::traits::{Boxable, Identifiable};
+    let fields = ListView::new()
+        .child("File", EditView::new().content("stacktraceflow.dot").with_id("export_file"))
+        .child("Call tree instead of call graph", Checkbox::new().with_id("export_tree"));
+    s.add_layer(Dialog::around(fields).title("Export to Graphviz DOT")
+        .button("Export", |s| {
+            let file = s.call_on_id("export_file", |view: &mut EditView| view.get_content())
+                .map_or(String::new(), |file| file.to_string());
+            let tree_mode = s.call_on_id("export_tree", |view: &mut Checkbox| view.is_checked())
+                .unwrap_or(false);
+            let mode = if tree_mode { export::Mode::Tree } else { export::Mode::Graph };
+            s.pop_layer();
+            let result = s.call_on_id("tree", |tree: &mut TreeType| {
+                export::write(tree, mode, Path::new(&file))
+            });
+            let dialog = match result {
+                Some(Err(e)) => Dialog::text(format!("Failed to write {}: {}", file, e))
+                    .title("Fail"),
+                _ => Dialog::text(format!("Written to {}", file)).title("Export"),
+            };
+            s.add_layer(dialog.button("Ok", |s| {s.pop_layer();}));
+        })
+        .button("Cancel", |s| { s.pop_layer(); })
+        .min_width(60));
+}
+
+/// Open the dialog to bookmark the selected call with a note
+fn bookmark_dialog(s: &mut cursive::Cursive) {
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    let path = s.call_on_id("tree", |tree: &mut TreeType| {
+        tree.row().and_then(|row| CallPath::of(tree, row))
+    }).unwrap_or(None);
+    let path = match path {
+        Some(path) => path,
+        None => {
+            s.add_layer(Dialog::text("Only calls can be bookmarked")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            return;
+        },
+    };
+    let note = unsafe { &CONFIGURATION.as_ref().unwrap().bookmarks }.iter()
+        .find(|bookmark| bookmark.path == path)
+        .map_or(String::new(), |bookmark| bookmark.note.clone());
+
+    use cursive::traits::{Boxable, Identifiable};
+    let submitted = path.clone();
+    let edit = EditView::new().content(note).on_submit(move |s, note| {
+        s.pop_layer();
+        set_bookmark(&submitted, note);
+    });
+    s.add_layer(Dialog::around(edit.with_id("note")).title("Bookmark with a note (optional)")
+        .button("Ok", move |s| {
+            let note = s.call_on_id("note", |view: &mut EditView| view.get_content());
+            s.pop_layer();
+            set_bookmark(&path, &note.unwrap_or_default());
+        })
+        .button("Cancel", |s| { s.pop_layer(); })
+        .min_width(60));
+}
+
+/// List the bookmarks in the panel
+fn fill_bookmarks(view: &mut SelectView<usize>) {
+    view.clear();
+    for (i, bookmark) in unsafe { &CONFIGURATION.as_ref().unwrap().bookmarks }.iter().enumerate() {
+        let note = if bookmark.note.is_empty() { "(no note)" } else { &bookmark.note };
+        if let Some(step) = bookmark.path.steps.last() {
+            view.add_item(format!("{}    {}", note, step.record), i);
+        }
+    }
+}
+
+/// Select the bookmarked call, expanding its ancestors
+fn go_to_bookmark(s: &mut cursive::Cursive, i: usize) {
+    let result = with_tree(s, |tree, state| {
+        let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+        let path = &configuration.bookmarks[i].path;
+        let lazy = &mut state.readers.lazy;
+        match path.find(tree, &mut |tree, row| load_children(configuration, lazy, tree, row)) {
+            Ok(Some(row)) => {
+                tree.set_selected_row(row);
+                Ok(())
+            },
+            Ok(None) => Err("The call is not in the tree any more".to_owned()),
+            Err(e) => Err(format!("Failed to read the subtree: {}", e)),
+        }
+    });
+    if let Some(Err(e)) = result {
+        s.add_layer(Dialog::text(e).title("Fail").button("Ok", |s| {s.pop_layer();}));
+    }
+    follow_selection(s);
+}
+
+/// Open the panel listing the bookmarks. Choosing one jumps to its call
+fn show_bookmarks(s: &mut cursive::Cursive) {
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    if unsafe { CONFIGURATION.as_ref().unwrap().bookmarks.is_empty() } {
+        s.add_layer(Dialog::text("There are no bookmarks. Add one with 'm'")
+                    .title("Bookmarks").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    use cursive::traits::{Boxable, Identifiable, Scrollable};
+    let mut list = SelectView::<usize>::new().on_submit(|s, &i: &usize| {
+        s.pop_layer();
+        go_to_bookmark(s, i);
+    });
+    fill_bookmarks(&mut list);
+    s.add_layer(Dialog::around(list.with_id("bookmarks").scrollable()).title("Bookmarks")
+        .button("Remove", |s| {
+            s.call_on_id("bookmarks", |view: &mut SelectView<usize>| {
+                if let Some(i) = view.selection() {
+                    unsafe {
+                        CONFIGURATION.as_mut().unwrap().bookmarks.remove(*i);
+                    }
+                    fill_bookmarks(view);
+                }
+            });
+        })
+        .button("Close", |s| { s.pop_layer(); })
+        .min_width(60));
+}
+
+/// Change the configuration and read the tree again, replaying the actions
+fn reload(
+    s: &mut cursive::Cursive,
+    change: impl FnOnce(&mut Configuration, &mut State) -> Result<(), &'static str>,
+) {
+    if state(s).showing_callers {
+        s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        return;
+    }
+    let result = with_tree(s, |tree, state| {
+        let configuration = unsafe { CONFIGURATION.as_mut().unwrap() };
+        let view = session::capture(configuration, tree);
+        change(configuration, state)?;
+        configuration.selected = tree.row().unwrap_or(0);
+        tree.clear();
+        state.readers = Readers::default();
+        state.hidden_view = None;
+        load_session(configuration, tree, &mut state.readers, &mut |_, _| {})?;
+        restore_view(configuration, &mut state.readers.lazy, tree, &view)
+            .map_err(|e| e.to_string())
+    });
+    if let Some(Err(e)) = result {
+        s.add_layer(Dialog::text(e).title("Fail").button("Ok", |s| {s.pop_layer();}));
+    }
 }
 
 fn main() {
+    use structopt::StructOpt;
+    let mut args = Cli::from_args();
+    let mut export = None;
+    if let Some(command) = args.command.take() {
+        match command {
+            Command::Convert{input, output, to} => {
+                if let Err(e) = binary::convert(&input, &output, to) {
+                    eprintln!("Failed to convert {}: {}", input.to_string_lossy(), e);
+                    std::process::exit(1);
+                }
+                return;
+            },
+            // Needs the tree, as it would be shown
+            Command::Export{output, mode} => export = Some((output, mode)),
+        }
+    }
+
     let configuration;
     unsafe {
-        CONFIGURATION = Some(Configuration::load());
+        CONFIGURATION = Some(Configuration::load(args));
         configuration = CONFIGURATION.as_ref().unwrap();
     }
 
     let mut tree = TreeType::new();
-    read_stacktraceflow_file(configuration, &mut tree);
+    let mut readers = Readers::default();
+    let progress = &mut |file: &Path, lines: usize| {
+        println!("{}: {} lines read", file.to_string_lossy(), lines);
+    };
+    let reports = match load_session(configuration, &mut tree, &mut readers, progress) {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
 
-    for act in &configuration.actions {
-        perform_action(&act, &mut tree);
+    if let Some((output, mode)) = export {
+        let result = restore_view(configuration, &mut readers.lazy, &mut tree, &configuration.view)
+            .map_err(|e| format!("Failed to restore the view: {}", e))
+            .and_then(|_| export::write(&tree, mode, &output).map_err(
+                |e| format!("Failed to write {}: {}", output.to_string_lossy(), e)));
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
     }
 
     use cursive::traits::Identifiable;
     let mut siv = cursive::Cursive::default();
-    type ScrollType = ScrollView<IdView<TreeType>>;
+    siv.set_user_data(State{readers: readers, ..State::default()});
     let scroll_view = ScrollType::new(tree.with_id("tree"))
         .scroll_y(false)
         .scroll_x(true);
 
-    siv.add_fullscreen_layer(scroll_view.with_id("scroll"));
+    use cursive::traits::Boxable;
+    // The preview pane is opened with 'v'
+    let layout = LinearLayout::horizontal().child(scroll_view.with_id("scroll").full_screen());
+    siv.add_fullscreen_layer(layout.with_id("layout"));
+
+    show_report(&mut siv, &reports);
+
+    // Go back to where the last session ended
+    let result = with_tree(&mut siv, |tree, state| {
+        restore_view(configuration, &mut state.readers.lazy, tree, &configuration.view)
+    });
+    if let Some(Err(e)) = result {
+        siv.add_layer(Dialog::text(format!("Failed to restore the view: {}", e))
+                      .title("Fail").button("Ok", |s| {s.pop_layer();}));
+    }
 
-    // Scroll on the x axis
     siv.call_on_id("tree", |tree: &mut TreeType| {
-        tree.set_on_select(|s, row| {
-            let x_position = s.call_on_id("tree", |tree: &mut TreeType| {
-                match (tree.first_col(row), tree.item_width(row)) {
-                    (Some(offset), Some(width)) => Some((offset, width)),
-                    _ => None
+        tree.set_on_select(on_select);
+    });
+    follow_selection(&mut siv);
+
+    // Read the children of lazily loaded nodes when they are expanded
+    if configuration.lazy {
+        siv.call_on_id("tree", |tree: &mut TreeType| {
+            tree.set_on_collapse(|s, row, is_collapsed, _| {
+                // Rows cut short by 'max_size' load the rest of their children when expanded again
+                if is_collapsed {
+                    return;
                 }
-            });
-            if let Some(Some((offset, width))) = x_position {
-                s.call_on_id("scroll", |s: &mut ScrollType| {
-                    let viewport = s.content_viewport();
-                    if viewport.left() > offset {
-                        s.set_offset((offset, viewport.top()));
-                    } else if viewport.right() < offset + width {
-                        s.set_offset((offset + width - viewport.width(), viewport.top()));
-                    }
+                let result = with_tree(s, |tree, state| {
+                    let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+                    expand_lazily(configuration, &mut state.readers.lazy, tree, row)
                 });
-            }
+                match result {
+                    Some(Err(e)) => {
+                        s.add_layer(Dialog::text(format!("Failed to read the subtree: {}", e))
+                                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                    },
+                    Some(Ok(false)) => {
+                        let max_size = unsafe { CONFIGURATION.as_ref().unwrap().max_size };
+                        s.add_layer(Dialog::text(format!(
+                            "Only some of the calls were loaded, because the tree has reached \
+                             'max_size' ({} items). Collapse other nodes, then collapse and \
+                             expand this one again to load the rest", max_size))
+                                    .title("Fail").button("Ok", |s| {s.pop_layer();}));
+                    },
+                    _ => {},
+                }
+            });
         });
-    });
+    }
 
     // [e]dit
     match configuration.source_code_info {
@@ -113,75 +1341,177 @@ fn main() {
         Some(ref sci) => {
             let sci = sci.clone();
             siv.add_global_callback('e', move |s| {
-                s.call_on_id("tree", |tree: &mut TreeType| {
-                    if let Some(row) = tree.row() {
-                        let record = tree.borrow_item(row).unwrap();
-                        let line_str: String = record.line.to_string();
-                        let command = sci.editor.replace("%F", &record.file)
-                                                .replace("%L", line_str.as_str());
-                        let command_arr: Vec<&str> = command.split(" ").collect();
-                        let program = command_arr.get(0).expect("The editor command is empty");
-                        let args = &command_arr[1..];
-
-                        use std::process::Command;
-                        Command::new(program)
-                                .current_dir(&sci.dir)
-                                .args(args)
-                                .status()
-                                .expect("Failed to run command");
-
+                if let Some(record) = selected_record(s) {
+                    if let Err(e) = open_in_editor(s, &sci, &record) {
+                        s.add_layer(Dialog::text(e).title("Fail")
+                                    .button("Ok", |s| {s.pop_layer();}));
                     }
-                });
+                }
             });
         },
     }
 
+    // Toggle following the selection in the [E]ditor
+    add_callback(&mut siv, 'E', |s| {
+        let sci = unsafe { CONFIGURATION.as_mut().unwrap().source_code_info.as_mut() };
+        match sci {
+            Some(ref sci) if !sci.backend.reuses_editor() => {
+                s.add_layer(Dialog::text("Following the selection needs an editor that is \
+                                          running already: choose the 'nvim', 'emacs' or 'code' \
+                                          editor backend")
+                            .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            },
+            Some(sci) => {
+                sci.follow = !sci.follow;
+                // Open the current call right away, and not the one waiting to be opened if
+                // following stops
+                state(s).followed = None;
+                FOLLOWING.fetch_add(1, Ordering::SeqCst);
+            },
+            None => {
+                s.add_layer(Dialog::text("Cannot follow the selection in the editor without the \
+                                          'editor' and 'dir' options supplied")
+                            .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            },
+        }
+    });
+
     // [d]elete only this row without children
-    siv.add_global_callback('d', move |s| {
-        s.call_on_id("tree", |tree: &mut TreeType| {
-            if let Some(row) = tree.row() {
+    add_callback(&mut siv, 'd', move |s| {
+        if state(s).showing_callers {
+            s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            return;
+        }
+        // The edit is saved by the path of calls leading to the row, and made only if it has one
+        let saved = with_tree(s, |tree, state| {
+            let row = tree.row()?;
+            let path = CallPath::of(tree, row);
+            if let Some(ref path) = path {
+                add_action(state, Action::DeleteAt(path.clone()));
                 tree.extract_item(row);
             }
+            Some(path.is_some())
         });
+        if let Some(Some(false)) = saved {
+            s.add_layer(Dialog::text("Only calls outside of aggregated nodes can be deleted on \
+                                      their own")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        }
     });
 
     // [D]elete all rows like this without their children
-    siv.add_global_callback('D', move |s| {
-        s.call_on_id("tree", |tree: &mut TreeType| {
+    add_callback(&mut siv, 'D', move |s| {
+        if state(s).showing_callers {
+            s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            return;
+        }
+        with_tree(s, |tree, state| {
             if let Some(row) = tree.row() {
-                if let Some(s) = tree.borrow_item(row) {
+                if let Some(s) = tree.borrow_item(row).and_then(Row::record) {
                     let action = Action::Delete(s.clone());
-                    perform_action(&action, tree);
-                    add_action(action);
+                    let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+                    perform_action(&action, tree, configuration, &mut state.readers.lazy);
+                    add_action(state, action);
                 }
             }
         });
     });
 
     // [r]ecursively remove
-    siv.add_global_callback('r', move |s| {
-        s.call_on_id("tree", |tree: &mut TreeType| {
-            if let Some(row) = tree.row() {
+    add_callback(&mut siv, 'r', move |s| {
+        if state(s).showing_callers {
+            s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            return;
+        }
+        // The edit is saved by the path of calls leading to the row, and made only if it has one
+        let saved = with_tree(s, |tree, state| {
+            let row = tree.row()?;
+            let path = CallPath::of(tree, row);
+            if let Some(ref path) = path {
+                add_action(state, Action::RemoveAt(path.clone()));
                 tree.remove_item(row);
             }
+            Some(path.is_some())
+        });
+        if let Some(Some(false)) = saved {
+            s.add_layer(Dialog::text("Only calls outside of aggregated nodes can be removed on \
+                                      their own")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        }
+    });
+
+    // Toggle the source pre[v]iew
+    add_callback(&mut siv, 'v', |s| {
+        if unsafe { CONFIGURATION.as_ref().unwrap().source_code_info.is_none() } {
+            s.add_layer(Dialog::text("Cannot preview the source without the 'editor' and 'dir' \
+                                      options supplied")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            return;
+        }
+        s.call_on_id("layout", |layout: &mut LinearLayout| {
+            if layout.len() > 1 {
+                layout.remove_child(1);
+            } else {
+                layout.add_child(preview_pane());
+            }
+        });
+        if let Some(preview) = state(s).preview.as_mut() {
+            preview.invalidate();
+        }
+        update_preview(s);
+    });
+
+    // Book[m]ark the current call
+    add_callback(&mut siv, 'm', bookmark_dialog);
+
+    // Show the [b]ookmarks
+    add_callback(&mut siv, 'b', show_bookmarks);
+
+    // [h]ide the calls matching regexes
+    add_callback(&mut siv, 'h', hide_dialog);
+
+    // [u]ndo the last edit
+    add_callback(&mut siv, 'u', |s| {
+        reload(s, |configuration, state| {
+            let action = configuration.actions.pop().ok_or("Nothing to undo")?;
+            state.redo.push(action);
+            Ok(())
+        });
+    });
+
+    // Redo the last edit undone
+    add_callback(&mut siv, cursive::event::Event::CtrlChar('r'), |s| {
+        reload(s, |configuration, state| {
+            let action = state.redo.pop().ok_or("Nothing to redo")?;
+            configuration.actions.push(action);
+            Ok(())
         });
     });
 
     // [R]ecursively remove all rows like this and their children
-    siv.add_global_callback('R', move |s| {
-        s.call_on_id("tree", |tree: &mut TreeType| {
+    add_callback(&mut siv, 'R', move |s| {
+        if state(s).showing_callers {
+            s.add_layer(Dialog::text("Switch back to the calls view with 'c' first")
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+            return;
+        }
+        with_tree(s, |tree, state| {
             if let Some(row) = tree.row() {
-                if let Some(s) = tree.borrow_item(row) {
+                if let Some(s) = tree.borrow_item(row).and_then(Row::record) {
                     let action = Action::Recursive(s.clone());
-                    perform_action(&action, tree);
-                    add_action(action);
+                    let configuration = unsafe { CONFIGURATION.as_ref().unwrap() };
+                    perform_action(&action, tree, configuration, &mut state.readers.lazy);
+                    add_action(state, action);
                 }
             }
         });
     });
 
     // Go to [p]arent
-    siv.add_global_callback('p', move |s| {
+    add_callback(&mut siv, 'p', move |s| {
         s.call_on_id("tree", |tree: &mut TreeType| {
             if let Some(row) = tree.row() {
                 if let Some(p) = tree.item_parent(row) {
@@ -191,15 +1521,96 @@ fn main() {
         });
     });
 
+    // Toggle [s]orting by duration
+    add_callback(&mut siv, 's', |s| {
+        let mut configuration = unsafe { CONFIGURATION.as_ref().unwrap().clone() };
+        configuration.sort_by_duration = !configuration.sort_by_duration;
+        rebuild(s, configuration);
+    });
+
+    // Toggle [a]ggregating the calls made through the same path
+    add_callback(&mut siv, 'a', |s| {
+        reload(s, |configuration, _| {
+            if configuration.lazy || !configuration.only.is_empty() {
+                return Err("Calls cannot be aggregated in lazy mode or with 'only' patterns");
+            }
+            configuration.aggregate = !configuration.aggregate;
+            Ok(())
+        });
+    });
+
+    // E[x]pand the aggregated calls into the individual ones
+    add_callback(&mut siv, 'x', |s| {
+        let result = with_tree(s, |tree, state| {
+            let row = tree.row()?;
+            let context = ContextTree::owning(&mut state.readers.contexts, tree, row)?;
+            Some(context.expand_calls(unsafe { CONFIGURATION.as_ref().unwrap() }, tree, row))
+        });
+        if let Some(Some(Err(e))) = result {
+            s.add_layer(Dialog::text(format!("Failed to read the calls: {}", e))
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        }
+    });
+
+    // Search for a regex
+    add_callback(&mut siv, '/', start_search);
+
+    // Go to the [n]ext match
+    add_callback(&mut siv, 'n', |s| {
+        let from = s.call_on_id("tree", |tree: &mut TreeType| tree.row()).unwrap_or(None);
+        search_from(s, from, true);
+    });
+
+    // Go to the previous match
+    add_callback(&mut siv, 'N', |s| {
+        let from = s.call_on_id("tree", |tree: &mut TreeType| tree.row()).unwrap_or(None);
+        search_from(s, from, false);
+    });
+
+    // Edit the [o]nly patterns and the depth
+    add_callback(&mut siv, 'o', edit_only);
+
+    // Show the [f]lat profile
+    add_callback(&mut siv, 'f', show_profile);
+
+    // Export the tree to [g]raphviz
+    add_callback(&mut siv, 'g', export_dialog);
+
+    // Switch between the calls and the [c]allers views, keeping the selected function
+    add_callback(&mut siv, 'c', |s| {
+        if let Err(e) = switch_view(s) {
+            s.add_layer(Dialog::text(format!("Failed to read the callers: {}", e))
+                        .title("Fail").button("Ok", |s| {s.pop_layer();}));
+        }
+    });
+
+    // Go to the next [t]hread
+    add_callback(&mut siv, 't', move |s| {
+        s.call_on_id("tree", |tree: &mut TreeType| {
+            if let Some(next) = threads::next_thread(tree, tree.row().unwrap_or(0), true) {
+                tree.set_selected_row(next);
+            }
+        });
+    });
+
+    // Go to the previous [T]hread
+    add_callback(&mut siv, 'T', move |s| {
+        s.call_on_id("tree", |tree: &mut TreeType| {
+            if let Some(previous) = threads::next_thread(tree, tree.row().unwrap_or(0), false) {
+                tree.set_selected_row(previous);
+            }
+        });
+    });
+
     // Go down
-    siv.add_global_callback('j', move |s| {
+    add_callback(&mut siv, 'j', move |s| {
         s.call_on_id("tree", |tree: &mut TreeType| {
             tree.focus_down(1);
         });
     });
 
     // Go up
-    siv.add_global_callback('k', move |s| {
+    add_callback(&mut siv, 'k', move |s| {
         s.call_on_id("tree", |tree: &mut TreeType| {
             tree.focus_up(1);
         });
@@ -211,14 +1622,7 @@ fn main() {
             Dialog::text("Would you like to save the current configuration?")
             .title("Quitting")
             .button("Yes", |s| {
-                let row = s.call_on_id("tree", |tree: &mut TreeType| {
-                    tree.row().unwrap_or(0)
-                }).unwrap_or(0);
-                unsafe {
-                    CONFIGURATION.as_ref().expect(
-                        "The CONFIGURATION object died before saving. Please report an issue"
-                    ).save(row);
-                }
+                save(s);
                 s.quit();
             })
             .button("No", |s| { s.quit(); })
@@ -237,11 +1641,25 @@ Navigation:
      PgDown - move 10 items down
        PgUp - move 10 items up
           p - move to parent
+          t - move to the next thread (when several threads are open)
+          T - move to the previous thread
     ← and → - scroll left/right (when the tree does not fit onto the screen)
+          / - search for a regex, matched against the rows as they are displayed,
+              including those inside collapsed nodes; the calls left out of the tree
+              because of the depth or size limits are found in the trace
+          n - move to the next match
+          N - move to the previous match
 
 Editing:
-          r - remove the current element recursively together with its children
-          d - delete the current element but keep its children
+          r - remove the current element recursively together with its children;
+              save this operation to config
+          d - delete the current element but keep its children;
+              save this operation to config
+          h - hide all the calls whose function or file path matches regexes, with or
+              without their children, optionally only below the current element;
+              save this operation to config
+          u - undo the last edit, reading the tree again
+     Ctrl-r - redo the last edit undone
           R - remove recursively all elements identical to the current one;
               save this operation to config when applicable
           D - delete all elements identical to the current one but keep their children;
@@ -250,6 +1668,24 @@ Editing:
 Miscellaneous:
           ? - show this help dialog
           e - open current item in an external editor
+          E - toggle opening every item selected in the editor
+          v - show or hide the source of the current item next to the tree
+          a - toggle merging the calls made through the same path into one node;
+              reloads the tree
+          x - expand the merged node into the individual calls
+          c - switch between the calls and the callers views, keeping the selected function;
+              the callers view lists every function with the call paths that reached it
+          m - bookmark the current element with an optional note, or edit its note
+          b - list the bookmarks; <Enter> goes to the chosen one
+          f - list every function with its number of calls, callers, callees, deepest
+              nesting and first and last call; the buttons sort by each column and <Enter>
+              goes to the first call
+          g - write the rows shown, without the collapsed ones, as a Graphviz DOT file:
+              a call graph weighted by the number of calls, or the tree in call order
+          o - edit the 'only' patterns and the depth; the tree is read again in the
+              background and the configuration is saved
+          s - toggle sorting the children by duration, longest first (needs timestamps);
+              reloads the tree
           q - offer to save the configuration and quit
             "))
             .title("Help")
@@ -0,0 +1,258 @@
+//! Addressing a row by the calls leading to it rather than by its position, so that it can be
+//! found again after the tree is read anew
+
+use serde::{Serialize, Deserialize};
+
+use crate::data::{Record, Row, TreeType};
+use crate::trace::ParseError;
+use crate::walk;
+
+/// A call on the path to a row
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    /// Number of earlier siblings with the same record
+    pub nth: usize,
+    pub record: Record,
+    /// Number of the call in the trace, if the row knows it. Unlike `nth`, it does not depend
+    /// on which siblings are shown, so it is compared instead whenever both sides have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub call: Option<usize>,
+}
+
+impl Step {
+    /// Whether the row is the call of the step. `nth` is the number of earlier siblings of the
+    /// row with the record of the step
+    fn is_shown_by(&self, row: Option<&Row>, nth: usize) -> bool {
+        match (row, self.call) {
+            (Some(Row::Call(item)), Some(call)) if item.call.is_some() => item.call == Some(call),
+            (row, _) => row.and_then(Row::record) == Some(&self.record) && nth == self.nth,
+        }
+    }
+
+    /// Whether both steps are the same call
+    fn is_same_call(&self, other: &Step) -> bool {
+        match (self.call, other.call) {
+            (Some(call), Some(other)) => call == other,
+            _ => self.record == other.record && self.nth == other.nth,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CallPath {
+    /// File name of the thread, if the session is split into threads
+    pub thread: Option<String>,
+    /// Calls from the top level down to the row
+    pub steps: Vec<Step>,
+}
+
+fn thread_name(row: Option<&Row>) -> Option<String> {
+    match row {
+        Some(Row::Thread(thread)) => thread.file.file_name().map(
+            |name| name.to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+impl CallPath {
+    /// Path to a shown row. None if the row, or any of its ancestors, is not a call
+    pub fn of(tree: &TreeType, mut row: usize) -> Option<CallPath> {
+        let mut steps = Vec::new();
+        let mut thread = None;
+        loop {
+            let parent = tree.item_parent(row);
+            let record = match tree.borrow_item(row).and_then(Row::record) {
+                Some(record) => record,
+                None if parent.is_none() => {
+                    thread = Some(thread_name(tree.borrow_item(row))?);
+                    break;
+                },
+                None => return None,
+            };
+            let call = tree.borrow_item(row).and_then(Row::item).and_then(|item| item.call);
+            let nth = walk::children(tree, parent).into_iter()
+                .take_while(|&sibling| sibling < row)
+                .filter(|&sibling| tree.borrow_item(sibling).and_then(Row::record) == Some(record))
+                .count();
+            steps.push(Step{nth: nth, record: record.clone(), call: call});
+            match parent {
+                Some(parent) => row = parent,
+                None => break,
+            }
+        }
+        if steps.is_empty() {
+            return None;
+        }
+        steps.reverse();
+        Some(CallPath{thread: thread, steps: steps})
+    }
+
+    /// Whether the path leads to the end of `other` or to one of its ancestors
+    pub fn leads_to(&self, other: &CallPath) -> bool {
+        self.thread == other.thread && self.steps.len() <= other.steps.len()
+            && self.steps.iter().zip(&other.steps).all(|(step, other)| step.is_same_call(other))
+    }
+
+    /// Row at the end of the path, expanding its ancestors. `load` is called for every ancestor
+    /// without children, whose children may not have been read yet
+    pub fn find(
+        &self,
+        tree: &mut TreeType,
+        load: &mut dyn FnMut(&mut TreeType, usize) -> Result<(), ParseError>,
+    ) -> Result<Option<usize>, ParseError> {
+        let mut parent = match self.thread {
+            Some(ref name) => {
+                let found = walk::children(tree, None).into_iter().find(
+                    |&row| thread_name(tree.borrow_item(row)).as_ref() == Some(name));
+                match found {
+                    Some(row) => Some(row),
+                    None => return Ok(None),
+                }
+            },
+            None => None,
+        };
+        for step in &self.steps {
+            if let Some(parent) = parent {
+                tree.expand_item(parent);
+                if walk::children(tree, Some(parent)).is_empty() {
+                    load(tree, parent)?;
+                }
+            }
+            let mut found = None;
+            let mut nth = 0;
+            for row in walk::children(tree, parent) {
+                let item = tree.borrow_item(row);
+                if step.is_shown_by(item, nth) {
+                    found = Some(row);
+                    break;
+                }
+                if item.and_then(Row::record) == Some(&step.record) {
+                    nth += 1;
+                }
+            }
+            match found {
+                Some(row) => parent = Some(row),
+                None => return Ok(None),
+            }
+        }
+        Ok(parent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Item;
+    use crate::threads::Thread;
+    use cursive_tree_view::Placement;
+
+    fn call(function: &str, call: Option<usize>) -> Row {
+        let mut item = Item::from(Record{
+            function: function.to_owned(),
+            file: "src/lib.rs".to_owned(),
+            line: 1,
+            column: 0,
+            last_line: 0,
+            last_column: 0,
+        });
+        item.call = call;
+        Row::Call(item)
+    }
+
+    /// main [f, g, f [h]], numbered in the order of the calls unless `numbered` is false
+    fn sample(numbered: bool) -> TreeType {
+        let number = |n| if numbered { Some(n) } else { None };
+        let mut tree = TreeType::new();
+        tree.insert_item(call("main", number(0)), Placement::LastChild, 0);
+        tree.insert_item(call("f", number(1)), Placement::LastChild, 0);
+        tree.insert_item(call("g", number(2)), Placement::LastChild, 0);
+        tree.insert_item(call("f", number(3)), Placement::LastChild, 0);
+        tree.insert_item(call("h", number(4)), Placement::LastChild, 3);
+        tree
+    }
+
+    fn find(path: &CallPath, tree: &mut TreeType) -> Option<usize> {
+        path.find(tree, &mut |_, _| Ok(())).unwrap()
+    }
+
+    #[test]
+    fn path_of_a_row_leads_back_to_it() {
+        let mut tree = sample(true);
+        for row in 0..tree.len() {
+            let path = CallPath::of(&tree, row).unwrap();
+            assert_eq!(find(&path, &mut tree), Some(row));
+        }
+        let path = CallPath::of(&tree, 4).unwrap();
+        assert_eq!(path.thread, None);
+        assert_eq!(path.steps.iter().map(|step| step.call).collect::<Vec<_>>(),
+                   vec![Some(0), Some(3), Some(4)]);
+        assert_eq!(path.steps[1].nth, 1);
+    }
+
+    #[test]
+    fn path_keeps_to_its_call_when_siblings_go() {
+        let mut tree = sample(true);
+        let second = CallPath::of(&tree, 3).unwrap();
+        let first = CallPath::of(&tree, 1).unwrap();
+        tree.remove_item(1);
+        // main, g, f, h
+        assert_eq!(find(&second, &mut tree), Some(2));
+        // Not the other call of the same function, which is now the first one shown
+        assert_eq!(find(&first, &mut tree), None);
+    }
+
+    #[test]
+    fn path_without_call_numbers_counts_the_siblings() {
+        let mut tree = sample(false);
+        let path = CallPath::of(&tree, 4).unwrap();
+        assert_eq!(path.steps[1].call, None);
+        assert_eq!(find(&path, &mut tree), Some(4));
+        tree.remove_item(2);
+        // main, f, f, h
+        assert_eq!(find(&path, &mut tree), Some(3));
+    }
+
+    #[test]
+    fn path_leads_to_its_subtree() {
+        let tree = sample(true);
+        let second = CallPath::of(&tree, 3).unwrap();
+        assert!(second.leads_to(&second));
+        assert!(second.leads_to(&CallPath::of(&tree, 4).unwrap()));
+        assert!(!second.leads_to(&CallPath::of(&tree, 0).unwrap()));
+        // The other call of the same function
+        assert!(!second.leads_to(&CallPath::of(&tree, 1).unwrap()));
+    }
+
+    #[test]
+    fn finding_a_path_expands_its_ancestors() {
+        let mut tree = sample(true);
+        let path = CallPath::of(&tree, 4).unwrap();
+        tree.collapse_item(3);
+        tree.collapse_item(0);
+        assert_eq!(tree.len(), 1);
+        let mut loaded = Vec::new();
+        let found = path.find(&mut tree, &mut |_, row| {
+            loaded.push(row);
+            Ok(())
+        }).unwrap();
+        assert_eq!(found, Some(4));
+        assert_eq!(tree.len(), 5);
+        // Only the rows without children are loaded
+        assert!(loaded.is_empty());
+    }
+
+    #[test]
+    fn path_goes_through_the_thread() {
+        let mut tree = TreeType::new();
+        for id in 1..3 {
+            let file = format!("trace.ThreadId({}).txt", id).into();
+            tree.insert_item(Row::Thread(Thread{id: Some(id), file: file}), Placement::After, 0);
+        }
+        // ThreadId(2) is inserted after ThreadId(1), then the calls under it
+        tree.insert_item(call("main", Some(0)), Placement::LastChild, 1);
+        let path = CallPath::of(&tree, 2).unwrap();
+        assert_eq!(path.thread.as_ref().map(String::as_str), Some("trace.ThreadId(2).txt"));
+        assert_eq!(find(&path, &mut tree), Some(2));
+        assert_eq!(CallPath::of(&tree, 1), None);
+    }
+}
@@ -0,0 +1,82 @@
+//! Source code of the selected call, shown next to the tree
+
+use std::path::{Path, PathBuf};
+
+use cursive::theme::Effect;
+use cursive::utils::markup::StyledString;
+
+use crate::config::SourceCodeInfo;
+use crate::data::Record;
+
+/// Lines shown before and after the function
+const CONTEXT: usize = 5;
+
+/// Longest function shown in full
+const MAX_LINES: usize = 500;
+
+pub struct Preview {
+    /// The file read last, which is likely to be needed again
+    path: Option<PathBuf>,
+    lines: Result<Vec<String>, String>,
+    /// The call shown at the moment
+    shown: Option<Record>,
+}
+
+impl Preview {
+    pub fn new() -> Self {
+        Preview{path: None, lines: Ok(Vec::new()), shown: None}
+    }
+
+    /// Show the current call again even if it has not changed, e.g. because the pane was closed
+    pub fn invalidate(&mut self) {
+        self.shown = None;
+    }
+
+    fn read(&mut self, path: &Path) {
+        if self.path.as_ref().map(PathBuf::as_path) == Some(path) {
+            return;
+        }
+        self.lines = std::fs::read_to_string(path)
+            .map(|text| text.lines().map(|line| line.replace('\t', "    ")).collect())
+            .map_err(|e| e.to_string());
+        self.path = Some(path.to_owned());
+    }
+
+    /// The function of the record with some lines around it, its first line highlighted, or
+    /// None if it is shown already
+    pub fn show(&mut self, sci: &SourceCodeInfo, record: Option<&Record>) -> Option<StyledString> {
+        if self.shown.as_ref() == record {
+            return None;
+        }
+        self.shown = record.cloned();
+        let record = match record {
+            Some(record) => record,
+            None => return Some(StyledString::new()),
+        };
+        let path = sci.path(&record.file);
+        self.read(&path);
+        let lines = match self.lines {
+            Ok(ref lines) => lines,
+            Err(ref e) => {
+                return Some(StyledString::plain(
+                    format!("Cannot read {}: {}", path.to_string_lossy(), e)));
+            },
+        };
+
+        let mut text = StyledString::plain(format!("{}:{}\n\n", record.file, record.line));
+        // Line numbers count from 1
+        let last_line = std::cmp::max(record.line, record.last_line);
+        let start = record.line.saturating_sub(CONTEXT + 1);
+        let end = std::cmp::min(last_line + CONTEXT, start + MAX_LINES);
+        let end = std::cmp::min(end, lines.len());
+        for (i, line) in lines.iter().enumerate().take(end).skip(start) {
+            let line = format!("{:>5}  {}\n", i + 1, line);
+            if i + 1 == record.line {
+                text.append_styled(line, Effect::Reverse);
+            } else {
+                text.append_plain(line);
+            }
+        }
+        Some(text)
+    }
+}
@@ -0,0 +1,126 @@
+//! Mapping the source paths recorded in the trace to local files
+//!
+//! The paths are whatever the compiler embedded: `/rustc/<hash>/library/...` for the standard
+//! library, `/home/<user>/.cargo/registry/...` for the dependencies, often of another machine,
+//! and paths relative to the workspace for the program itself. The first rule matching a path
+//! rewrites it, and a path that is still relative is looked up in `dir`.
+
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use crate::data::optional_regex;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    /// Replace this prefix of the path, like `--remap-path-prefix` of rustc does. It matches
+    /// whole components only, so `/src` does not match `/srcfoo`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Replace the first match of this regex. `$1` and `${name}` in `to` stand for its groups
+    #[serde(default, with = "optional_regex", skip_serializing_if = "Option::is_none")]
+    pub regex: Option<Regex>,
+    /// Replacement. A leading `~/` stands for the home directory
+    pub to: String,
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => path.to_owned(),
+    }
+}
+
+/// A prefix rule, written as FROM=TO on the command line
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        match rule.find('=') {
+            Some(pos) => Ok(Rule{
+                prefix: Some(rule[..pos].to_owned()),
+                regex: None,
+                to: rule[pos + 1..].to_owned(),
+            }),
+            None => Err(format!("Expected FROM=TO, got '{}'", rule)),
+        }
+    }
+}
+
+impl Rule {
+    /// The path rewritten, if the rule applies to it
+    fn apply(&self, file: &str) -> Option<String> {
+        if let Some(ref prefix) = self.prefix {
+            let rest = Path::new(file).strip_prefix(prefix).ok()?;
+            let to = expand_home(&self.to);
+            if rest.as_os_str().is_empty() {
+                return Some(to);
+            }
+            return Some(Path::new(&to).join(rest).to_string_lossy().into_owned());
+        }
+        let regex = self.regex.as_ref()?;
+        if !regex.is_match(file) {
+            return None;
+        }
+        Some(expand_home(&regex.replace(file, self.to.as_str())))
+    }
+}
+
+/// The path of the file after applying the first matching rule, still relative if it was
+/// relative and no rule made it absolute
+pub fn file<'a>(rules: impl IntoIterator<Item = &'a Rule>, file: &str) -> String {
+    rules.into_iter().find_map(|rule| rule.apply(file)).unwrap_or_else(|| file.to_owned())
+}
+
+/// Where the file is on this machine
+pub fn path<'a>(rules: impl IntoIterator<Item = &'a Rule>, dir: &Path, file: &str) -> PathBuf {
+    dir.join(self::file(rules, file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(prefix: Option<&str>, regex: Option<&str>, to: &str) -> Rule {
+        Rule{
+            prefix: prefix.map(str::to_owned),
+            regex: regex.map(|regex| Regex::new(regex).unwrap()),
+            to: to.to_owned(),
+        }
+    }
+
+    #[test]
+    fn prefix_matches_whole_components() {
+        let rules = [rule(Some("/home/ci/src"), None, "/work")];
+        assert_eq!(file(&rules, "/home/ci/src/lib.rs"), "/work/lib.rs");
+        assert_eq!(file(&rules, "/home/ci/src"), "/work");
+        assert_eq!(file(&rules, "/home/ci/srcfoo/lib.rs"), "/home/ci/srcfoo/lib.rs");
+        // A trailing slash in the prefix changes nothing
+        let rules = [rule(Some("/home/ci/src/"), None, "/work/")];
+        assert_eq!(file(&rules, "/home/ci/src/lib.rs"), "/work/lib.rs");
+    }
+
+    #[test]
+    fn regex_replaces_its_first_match() {
+        let rules = [rule(None, Some("^/rustc/[0-9a-f]+/(library)/"), "/rust/$1/")];
+        assert_eq!(file(&rules, "/rustc/abc123/library/core/src/lib.rs"),
+                   "/rust/library/core/src/lib.rs");
+        assert_eq!(file(&rules, "src/main.rs"), "src/main.rs");
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rules = [rule(Some("/a"), None, "/first"), rule(Some("/a/b"), None, "/second")];
+        assert_eq!(file(&rules, "/a/b/c.rs"), "/first/b/c.rs");
+        assert_eq!(path(&rules[1..], Path::new("/dir"), "x/c.rs"), PathBuf::from("/dir/x/c.rs"));
+    }
+
+    #[test]
+    fn rule_from_command_line() {
+        let rule: Rule = "/home/ci=/home/me".parse().unwrap();
+        assert_eq!(rule.prefix.as_ref().map(String::as_str), Some("/home/ci"));
+        assert_eq!(rule.to, "/home/me");
+        assert!("/home/ci".parse::<Rule>().is_err());
+    }
+}
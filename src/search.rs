@@ -0,0 +1,308 @@
+//! Searching for functions by a regex over how their rows are displayed
+//!
+//! The rows of the tree are searched first, including those inside collapsed nodes. Calls that
+//! were never inserted into the tree, because of `depth` or `max_size`, are found by reading the
+//! trace again, once for every regex.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use regex::Regex;
+
+use crate::config::Configuration;
+use crate::data::{Record, Row, TreeType};
+use crate::init::{skip_or_fail, ReadReport};
+use crate::threads;
+use crate::trace::{self, Event, ParseError, RecordId};
+use crate::walk;
+
+/// State of the last search, kept for the next and previous matches
+pub struct Search {
+    pub regex: Regex,
+    /// Calls in the trace matching the regex, read the first time none was found in the tree
+    pub trace: Option<TraceMatches>,
+    /// Number of the match in the trace selected last, when none was found in the tree
+    pub trace_match: Option<usize>,
+}
+
+impl Search {
+    pub fn new(regex: Regex) -> Self {
+        Search{regex: regex, trace: None, trace_match: None}
+    }
+}
+
+pub fn matches(regex: &Regex, row: Option<&Row>) -> bool {
+    row.and_then(Row::record).map_or(false, |record| regex.is_match(&record.to_string()))
+}
+
+/// Select the next (or the previous) matching row after `from`, wrapping around at the end of
+/// the tree. Returns whether there was any
+pub fn in_tree(tree: &mut TreeType, regex: &Regex, from: Option<usize>, forward: bool) -> bool {
+    if from.map_or(false, |from| from >= tree.len()) {
+        return in_tree(tree, regex, None, forward);
+    }
+    match walk::reveal(tree, from, forward, |tree, row| matches(regex, tree.borrow_item(row))) {
+        Some(row) => {
+            tree.set_selected_row(row);
+            true
+        },
+        None => false,
+    }
+}
+
+/// A call on the path to a match in the trace
+#[derive(Clone)]
+pub struct Step {
+    record: Record,
+    /// Number of the call in the file, as in `TraceIndex::calls`
+    call: usize,
+    /// Number of earlier calls of the same function with the same parent
+    ordinal: usize,
+}
+
+/// A call found in the trace, with the path of calls leading to it
+pub struct Match {
+    /// File of the thread, if the session is split into threads
+    pub thread: Option<PathBuf>,
+    pub path: Vec<Step>,
+}
+
+impl Match {
+    pub fn record(&self) -> &Record {
+        &self.path.last().unwrap().record
+    }
+
+    /// The calls leading to the match, outermost first
+    pub fn describe(&self) -> String {
+        self.path.iter().map(|step| step.record.to_string()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// A call on the path to a match, stored once for all the matches below it
+struct StoredStep {
+    parent: Option<usize>,
+    /// Index into `TraceMatches::records`
+    record: usize,
+    call: usize,
+    ordinal: usize,
+}
+
+/// All the calls in the trace (across the files of all the threads) of the functions matching
+/// a regex, found in a single read
+pub struct TraceMatches {
+    threads: Vec<Option<PathBuf>>,
+    records: Vec<Record>,
+    steps: Vec<StoredStep>,
+    /// Thread (index into `threads`) and innermost step of each match, in the order of the trace
+    matches: Vec<(usize, usize)>,
+}
+
+/// Call on the stack while searching
+struct Frame {
+    record: RecordId,
+    call: usize,
+    ordinal: usize,
+    /// Number of calls of each function made by this one so far
+    children: HashMap<RecordId, usize>,
+    /// The step stored for the call, once a match has been found below it
+    stored: Option<usize>,
+}
+
+impl TraceMatches {
+    pub fn find(configuration: &Configuration, regex: &Regex) -> Result<Self, ParseError> {
+        let threads: Vec<Option<PathBuf>> = match threads::discover(&configuration.file)? {
+            None => vec![None],
+            Some(threads) => threads.into_iter().map(|thread| Some(thread.file)).collect(),
+        };
+        let mut result = TraceMatches{
+            threads: Vec::new(),
+            records: Vec::new(),
+            steps: Vec::new(),
+            matches: Vec::new(),
+        };
+        // Problems have been reported when the tree was loaded
+        let mut report = ReadReport::new();
+
+        for (thread, file) in threads.iter().enumerate() {
+            let mut reader = trace::open(file.as_ref().unwrap_or(&configuration.file))?;
+            let mut is_match: Vec<Option<bool>> = Vec::new();
+            // Maps ids of the reader to indexes into `records`
+            let mut ids: Vec<Option<usize>> = Vec::new();
+            let mut stack: Vec<Frame> = Vec::new();
+            let mut top_level: HashMap<RecordId, usize> = HashMap::new();
+            let mut calls = 0;
+            while let Some(event) = reader.next_event() {
+                match event {
+                    Ok(Event::Push(record)) => {
+                        let siblings = match stack.last_mut() {
+                            Some(parent) => &mut parent.children,
+                            None => &mut top_level,
+                        };
+                        let ordinal = siblings.entry(record).or_insert(0);
+                        *ordinal += 1;
+                        let ordinal = *ordinal - 1;
+                        stack.push(Frame{
+                            record: record,
+                            call: calls,
+                            ordinal: ordinal,
+                            children: HashMap::new(),
+                            stored: None,
+                        });
+                        calls += 1;
+
+                        if is_match.len() <= record {
+                            is_match.resize(record + 1, None);
+                            ids.resize(record + 1, None);
+                        }
+                        if is_match[record].is_none() {
+                            let display = reader.records()[record].to_string();
+                            is_match[record] = Some(regex.is_match(&display));
+                        }
+                        if is_match[record] == Some(true) {
+                            result.store(&mut stack, &mut ids, reader.records(), thread);
+                        }
+                    },
+                    Ok(Event::Pop(record)) => {
+                        // Close the missing '-' lines up to the call this line belongs to
+                        let pos = match record {
+                            Some(record) => stack.iter().rposition(|frame| frame.record == record),
+                            None => stack.len().checked_sub(1),
+                        };
+                        // Unbalanced lines have been reported when the tree was loaded
+                        if let Some(pos) = pos {
+                            stack.truncate(pos);
+                        }
+                    },
+                    Err(e) => skip_or_fail(configuration, &mut report, e)?,
+                }
+            }
+        }
+        result.threads = threads;
+        Ok(result)
+    }
+
+    /// Add the call on top of the stack to the matches, storing the calls leading to it that
+    /// have not been stored for an earlier match
+    fn store(
+        &mut self,
+        stack: &mut [Frame],
+        ids: &mut [Option<usize>],
+        records: &[Record],
+        thread: usize,
+    ) {
+        // The calls stored already are always at the bottom of the stack
+        let first = stack.iter().rposition(|frame| frame.stored.is_some()).map_or(0, |i| i + 1);
+        for i in first..stack.len() {
+            let record = stack[i].record;
+            let record = match ids[record] {
+                Some(id) => id,
+                None => {
+                    self.records.push(records[record].clone());
+                    ids[record] = Some(self.records.len() - 1);
+                    self.records.len() - 1
+                },
+            };
+            self.steps.push(StoredStep{
+                parent: if i == 0 { None } else { stack[i - 1].stored },
+                record: record,
+                call: stack[i].call,
+                ordinal: stack[i].ordinal,
+            });
+            stack[i].stored = Some(self.steps.len() - 1);
+        }
+        self.matches.push((thread, stack.last().unwrap().stored.unwrap()));
+    }
+
+    /// Number of matches
+    pub fn count(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// The `nth` match, counting from 0
+    pub fn get(&self, nth: usize) -> Match {
+        let (thread, mut step) = self.matches[nth];
+        let mut path = Vec::new();
+        loop {
+            let stored = &self.steps[step];
+            path.push(Step{
+                record: self.records[stored.record].clone(),
+                call: stored.call,
+                ordinal: stored.ordinal,
+            });
+            match stored.parent {
+                Some(parent) => step = parent,
+                None => break,
+            }
+        }
+        path.reverse();
+        Match{thread: self.threads[thread].clone(), path: path}
+    }
+}
+
+/// Whether the row shows the call of the step
+fn shows(tree: &TreeType, row: usize, step: &Step, ordinal: usize) -> bool {
+    match tree.borrow_item(row) {
+        // Lazily loaded rows know their call
+        Some(Row::Call(item)) if item.call.is_some() => item.call == Some(step.call),
+        Some(Row::Call(item)) => item.record == step.record && ordinal == step.ordinal,
+        // All the calls through the same path are merged into one
+        Some(Row::Context(context)) => context.record == step.record,
+        _ => false,
+    }
+}
+
+/// Expand the path to the match as far as it is shown in the tree, calling `load` for every
+/// row whose children may not have been read yet. Returns the row of the deepest call on the
+/// path that is shown, if any, and whether it is the match itself
+pub fn closest(
+    tree: &mut TreeType,
+    found: &Match,
+    load: &mut dyn FnMut(&mut TreeType, usize) -> Result<(), ParseError>,
+) -> Result<(Option<usize>, bool), ParseError> {
+    let mut parent = None;
+    if let Some(ref file) = found.thread {
+        parent = walk::children(tree, None).into_iter().find(|&row| match tree.borrow_item(row) {
+            Some(Row::Thread(thread)) => &thread.file == file,
+            _ => false,
+        });
+        if parent.is_none() {
+            return Ok((None, false));
+        }
+    }
+    let mut closest = None;
+    for (depth, step) in found.path.iter().enumerate() {
+        if let Some(parent) = parent {
+            tree.expand_item(parent);
+            if walk::children(tree, Some(parent)).is_empty() {
+                load(tree, parent)?;
+            }
+        }
+        let mut ordinals: HashMap<&Record, usize> = HashMap::new();
+        let mut next = None;
+        for row in walk::children(tree, parent) {
+            let ordinal = match tree.borrow_item(row).and_then(Row::record) {
+                Some(record) => {
+                    let ordinal = ordinals.entry(record).or_insert(0);
+                    *ordinal += 1;
+                    *ordinal - 1
+                },
+                None => continue,
+            };
+            if shows(tree, row, step, ordinal) {
+                next = Some(row);
+                break;
+            }
+        }
+        match next {
+            Some(row) => {
+                closest = Some(row);
+                parent = Some(row);
+                if depth + 1 == found.path.len() {
+                    return Ok((closest, true));
+                }
+            },
+            None => break,
+        }
+    }
+    Ok((closest, false))
+}
@@ -0,0 +1,104 @@
+//! Where the user was in the tree: the selected row and the nodes collapsed or expanded by hand,
+//! all addressed by call paths so that they are found again when the tree is read anew
+
+use serde::{Serialize, Deserialize};
+
+use crate::config::Configuration;
+use crate::data::{Row, TreeType};
+use crate::path::CallPath;
+use crate::trace::ParseError;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ViewState {
+    pub selected: Option<CallPath>,
+    /// Nodes collapsed although they are expanded when the tree is read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collapsed: Vec<CallPath>,
+    /// Nodes expanded although they are collapsed when the tree is read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expanded: Vec<CallPath>,
+}
+
+/// Number of calls above the row, not counting thread nodes
+fn level(tree: &TreeType, row: usize) -> usize {
+    let mut level = 0;
+    let mut parent = tree.item_parent(row);
+    while let Some(row) = parent {
+        if let Some(Row::Call(_)) | Some(Row::Context(_)) = tree.borrow_item(row) {
+            level += 1;
+        }
+        parent = tree.item_parent(row);
+    }
+    level
+}
+
+/// Whether the row is collapsed right after the tree is read: folded runs and recursion are,
+/// and so are the lazily loaded calls at the depth limit
+fn collapsed_by_default(configuration: &Configuration, tree: &TreeType, row: usize) -> bool {
+    match tree.borrow_item(row) {
+        Some(Row::Call(item)) if item.repeated > 1 || item.recursion > 1 => true,
+        Some(Row::Call(_)) if configuration.lazy => {
+            level(tree, row) + 1 >= configuration.depth as usize
+        },
+        _ => false,
+    }
+}
+
+/// Whether the row has children hidden by collapsing it. Finding out expands it for a moment
+fn is_collapsed(tree: &mut TreeType, row: usize) -> bool {
+    let len = tree.len();
+    tree.expand_item(row);
+    if tree.len() == len {
+        return false;
+    }
+    tree.collapse_item(row);
+    true
+}
+
+fn has_shown_children(tree: &TreeType, row: usize) -> bool {
+    row + 1 < tree.len() && tree.item_parent(row + 1) == Some(row)
+}
+
+/// Record the selected row and the nodes whose state differs from when the tree was read. The
+/// nodes inside collapsed ones are not looked at
+pub fn capture(configuration: &Configuration, tree: &mut TreeType) -> ViewState {
+    let mut state = ViewState::default();
+    state.selected = tree.row().and_then(|row| CallPath::of(tree, row));
+    for row in 0..tree.len() {
+        let by_default = collapsed_by_default(configuration, tree, row);
+        if !by_default && is_collapsed(tree, row) {
+            state.collapsed.extend(CallPath::of(tree, row));
+        } else if by_default && has_shown_children(tree, row) {
+            state.expanded.extend(CallPath::of(tree, row));
+        }
+    }
+    state
+}
+
+/// Expand and collapse the nodes as recorded, and select the row. `load` is called for every
+/// row to expand that has no children, whose children may not have been read yet
+pub fn restore(
+    tree: &mut TreeType,
+    state: &ViewState,
+    load: &mut dyn FnMut(&mut TreeType, usize) -> Result<(), ParseError>,
+) -> Result<(), ParseError> {
+    for path in &state.expanded {
+        if let Some(row) = path.find(tree, load)? {
+            tree.expand_item(row);
+            if !has_shown_children(tree, row) {
+                load(tree, row)?;
+            }
+        }
+    }
+    for path in &state.collapsed {
+        if let Some(row) = path.find(tree, load)? {
+            tree.collapse_item(row);
+        }
+    }
+    if let Some(ref path) = state.selected {
+        if let Some(row) = path.find(tree, load)? {
+            tree.set_selected_row(row);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! Flat profile of the loaded tree: every function with how often, from where and how deep it
+//! was called
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use crate::data::{Record, Row, TreeType};
+use crate::walk;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Calls,
+    Callers,
+    Callees,
+    Depth,
+    First,
+    Last,
+}
+
+impl Column {
+    pub const ALL: [Column; 6] = [
+        Column::Calls, Column::Callers, Column::Callees,
+        Column::Depth, Column::First, Column::Last,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Calls   => "Calls",
+            Column::Callers => "Callers",
+            Column::Callees => "Callees",
+            Column::Depth   => "Depth",
+            Column::First   => "First",
+            Column::Last    => "Last",
+        }
+    }
+}
+
+/// Statistics of one function
+pub struct Stat {
+    pub record: Record,
+    pub calls: usize,
+    callers: HashSet<Record>,
+    callees: HashSet<Record>,
+    /// Deepest nesting of a call, 1 at the top level
+    pub max_depth: usize,
+    /// Positions of the first and last calls among all the calls of the tree, counting from 1
+    pub first: usize,
+    pub last: usize,
+}
+
+impl Stat {
+    fn new(record: Record) -> Self {
+        Stat{
+            record: record,
+            calls: 0,
+            callers: HashSet::new(),
+            callees: HashSet::new(),
+            max_depth: 0,
+            first: 0,
+            last: 0,
+        }
+    }
+
+    pub fn callers(&self) -> usize {
+        self.callers.len()
+    }
+
+    pub fn callees(&self) -> usize {
+        self.callees.len()
+    }
+
+    pub fn header() -> String {
+        format!("{:>9} {:>8} {:>8} {:>6} {:>9} {:>9}    Function", "Calls", "Callers", "Callees",
+                "Depth", "First", "Last")
+    }
+}
+
+impl std::fmt::Display for Stat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>9} {:>8} {:>8} {:>6} {:>9} {:>9}    {}", self.calls, self.callers(),
+               self.callees(), self.max_depth, self.first, self.last, self.record.spanned())
+    }
+}
+
+/// Number of calls the row stands for, or None if it is not a call
+pub fn calls(row: &Row) -> Option<usize> {
+    match row {
+        Row::Call(item) => Some(item.repeated),
+        Row::Context(context) => Some(context.count),
+        Row::Thread(_) | Row::Caller(_) => None,
+    }
+}
+
+/// Collect the statistics of every function in the tree, including the collapsed parts
+pub fn build(tree: &mut TreeType) -> Vec<Stat> {
+    let mut stats: Vec<Stat> = Vec::new();
+    let mut ids: HashMap<Record, usize> = HashMap::new();
+    let mut position = 0;
+    walk::for_each(tree, |tree, row, depth| {
+        let (record, count) = match tree.borrow_item(row) {
+            Some(item) => match (item.record(), calls(item)) {
+                (Some(record), Some(count)) => (record, count),
+                _ => return,
+            },
+            None => return,
+        };
+        let caller = tree.item_parent(row).and_then(|parent| tree.borrow_item(parent))
+            .and_then(Row::record);
+        let id = *ids.entry(record.clone()).or_insert_with(|| {
+            stats.push(Stat::new(record.clone()));
+            stats.len() - 1
+        });
+
+        let stat = &mut stats[id];
+        if stat.calls == 0 {
+            stat.first = position + 1;
+        }
+        stat.calls += count;
+        position += count;
+        stat.last = position;
+        stat.max_depth = std::cmp::max(stat.max_depth, depth + 1);
+        if let Some(caller) = caller {
+            stat.callers.insert(caller.clone());
+            if let Some(&caller) = ids.get(caller) {
+                stats[caller].callees.insert(record.clone());
+            }
+        }
+    });
+    stats
+}
+
+/// Sort by the column, largest first for the counts and earliest first for the positions
+pub fn sort(stats: &mut [Stat], column: Column) {
+    match column {
+        Column::Calls   => stats.sort_by_key(|stat| Reverse(stat.calls)),
+        Column::Callers => stats.sort_by_key(|stat| Reverse(stat.callers())),
+        Column::Callees => stats.sort_by_key(|stat| Reverse(stat.callees())),
+        Column::Depth   => stats.sort_by_key(|stat| Reverse(stat.max_depth)),
+        Column::First   => stats.sort_by_key(|stat| stat.first),
+        Column::Last    => stats.sort_by_key(|stat| stat.last),
+    }
+}
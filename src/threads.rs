@@ -0,0 +1,143 @@
+//! Sessions made of one StackTraceFlow file per thread
+//!
+//! The instrumented program writes a separate `stacktraceflow.ThreadId(N).txt` for each of its
+//! threads. Passing a directory or a glob pattern instead of a single file opens all of them
+//! together, each under a top-level node of its own.
+
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::data::{Row, TreeType};
+
+/// A thread of the traced program and the file with its trace
+#[derive(Clone, Debug)]
+pub struct Thread {
+    /// The N in ThreadId(N), if the filename contains it
+    pub id: Option<u64>,
+    pub file: PathBuf,
+}
+
+impl Thread {
+    fn new(file: PathBuf) -> Self {
+        let re = Regex::new(r"ThreadId\((\d+)\)").expect("Failed to compile the thread id regex");
+        let name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let id = re.captures(&name).and_then(|cap| cap[1].parse().ok());
+        Thread{id: id, file: file}
+    }
+}
+
+impl std::fmt::Display for Thread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "ThreadId({})    {}", id, self.file.to_string_lossy()),
+            None => write!(f, "{}", self.file.to_string_lossy()),
+        }
+    }
+}
+
+fn not_found(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, message)
+}
+
+/// Whether the path may hold a trace. Index caches lying next to the traces are left out
+fn is_trace(path: &Path) -> bool {
+    path.is_file() && path.extension().map_or(true, |ext| ext != "stfindex")
+}
+
+/// Files of all the threads in a directory, recognized by the ThreadId in their names
+fn in_directory(dir: &Path) -> std::io::Result<Vec<Thread>> {
+    let mut threads = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_trace(&path) {
+            let thread = Thread::new(path);
+            if thread.id.is_some() {
+                threads.push(thread);
+            }
+        }
+    }
+    Ok(threads)
+}
+
+/// Files matching a glob pattern
+fn matching(pattern: &str) -> std::io::Result<Vec<Thread>> {
+    let paths = glob::glob(pattern).map_err(
+        |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut threads = Vec::new();
+    for path in paths {
+        let path = path.map_err(std::io::Error::from)?;
+        if is_trace(&path) {
+            threads.push(Thread::new(path));
+        }
+    }
+    Ok(threads)
+}
+
+/// Threads of the session opened with `path`, sorted by their ids.
+///
+/// Returns None if `path` is a single file, which is shown without a thread node above it.
+pub fn discover(path: &Path) -> std::io::Result<Option<Vec<Thread>>> {
+    let as_string = path.to_string_lossy();
+    let mut threads = if path.is_dir() {
+        in_directory(path)?
+    } else if !path.exists() && as_string.contains(|c| c == '*' || c == '?' || c == '[') {
+        matching(&as_string)?
+    } else {
+        return Ok(None);
+    };
+    if threads.is_empty() {
+        return Err(not_found(format!("No per-thread StackTraceFlow files in {}", as_string)));
+    }
+    threads.sort_by(|a, b| (a.id, &a.file).cmp(&(b.id, &b.file)));
+    Ok(Some(threads))
+}
+
+/// The thread whose subtree contains the given row, if the tree is split into threads
+pub fn thread_of(tree: &TreeType, mut row: usize) -> Option<&Thread> {
+    while let Some(parent) = tree.item_parent(row) {
+        row = parent;
+    }
+    match tree.borrow_item(row) {
+        Some(Row::Thread(thread)) => Some(thread),
+        _ => None,
+    }
+}
+
+/// Row of the next (or the previous) thread node, counting from the given row
+pub fn next_thread(tree: &TreeType, row: usize, forward: bool) -> Option<usize> {
+    let is_thread = |i: &usize| match tree.borrow_item(*i) {
+        Some(Row::Thread(_)) => true,
+        _ => false,
+    };
+    if forward {
+        (row + 1..tree.len()).find(is_thread)
+    } else {
+        (0..row).rev().find(is_thread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_caches_are_not_threads() {
+        let dir = std::env::temp_dir().join(
+            format!("stacktraceflow-{}-threads", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let trace = "stacktraceflow.ThreadId(1).txt";
+        for name in &[trace.to_owned(), format!("{}.stfindex", trace)] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let pattern = dir.join("*ThreadId*");
+        let found = (discover(&dir).unwrap(), discover(&pattern).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+
+        for threads in &[found.0.unwrap(), found.1.unwrap()] {
+            assert_eq!(threads.len(), 1);
+            assert_eq!(threads[0].id, Some(1));
+            assert_eq!(threads[0].file.extension().unwrap(), "txt");
+        }
+    }
+}
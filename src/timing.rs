@@ -0,0 +1,68 @@
+//! Durations of calls in traces with timestamps
+
+use std::cmp::Reverse;
+
+use cursive_tree_view::Placement;
+
+use crate::data::{Row, TreeType};
+
+/// Format nanoseconds in the largest unit that keeps the number above 1
+pub fn format_duration(nanoseconds: u64) -> String {
+    let units = [(1_000_000_000, "s"), (1_000_000, "ms"), (1_000, "µs")];
+    for &(size, unit) in &units {
+        if nanoseconds >= size {
+            return format!("{:.1}{}", nanoseconds as f64 / size as f64, unit);
+        }
+    }
+    format!("{}ns", nanoseconds)
+}
+
+fn duration(row: &Row) -> Option<u64> {
+    match row {
+        Row::Call(item) => item.inclusive,
+        Row::Context(context) => context.duration,
+        Row::Thread(_) | Row::Caller(_) => None,
+    }
+}
+
+/// Insert `row` and, recursively, its children, longest first
+fn insert_sorted(
+    tree: &mut TreeType,
+    items: &mut Vec<Option<Row>>,
+    children: &[Vec<usize>],
+    row: usize,
+    placement: Placement,
+    target: usize,
+) -> Option<usize> {
+    let new_row = tree.insert_item(items[row].take()?, placement, target)?;
+    for &child in &children[row + 1] {
+        insert_sorted(tree, items, children, child, Placement::LastChild, new_row);
+    }
+    Some(new_row)
+}
+
+/// Order the children of every node by their duration, longest first. Calls without one keep
+/// their order after the others.
+///
+/// Only works on a freshly loaded tree, in which nothing is collapsed and every node is a row.
+pub fn sort_by_duration(tree: &mut TreeType) {
+    // Children of each row, shifted by one to make room for the top level at 0
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); tree.len() + 1];
+    for row in 0..tree.len() {
+        let parent = tree.item_parent(row).map_or(0, |parent| parent + 1);
+        children[parent].push(row);
+    }
+    let mut items: Vec<Option<Row>> = tree.take_items().into_iter().map(Some).collect();
+    for siblings in children.iter_mut() {
+        siblings.sort_by_key(|&row| Reverse(items[row].as_ref().and_then(duration)));
+    }
+
+    let mut previous = None;
+    for &row in &children[0] {
+        previous = match previous {
+            None => insert_sorted(tree, &mut items, &children, row, Placement::LastChild, 0),
+            Some(previous) => insert_sorted(
+                tree, &mut items, &children, row, Placement::After, previous),
+        };
+    }
+}
@@ -0,0 +1,288 @@
+//! Reading StackTraceFlow files, in either the text or the binary format
+
+use std::io::BufRead;
+use std::collections::HashMap;
+use std::path::Path;
+use regex::Regex;
+
+use crate::data::Record;
+use crate::binary;
+use crate::input::Input;
+
+/// Index into the table of records seen so far by a TraceReader
+pub type RecordId = usize;
+
+/// Problem encountered while reading a StackTraceFlow file
+#[derive(Debug)]
+pub enum ParseError {
+    /// The file could not be opened or read
+    Io(std::io::Error),
+    /// The line starts with neither '+' nor '-'
+    BadPrefix { line_no: usize, line: String },
+    /// The line does not have the 'function @file:line:column: last_line:last_column [timestamp]'
+    /// format. `line` includes the sign
+    RegexMismatch { line_no: usize, line: String },
+    /// A line or column number in the line does not fit into usize. `line` includes the sign
+    BadLineNumber { line_no: usize, line: String },
+    /// A '-' line does not match any function on the stack
+    UnbalancedPop { line_no: usize, record: String, expected: String },
+    /// A '-' line was read while the stack was empty
+    EmptyStackPop { line_no: usize },
+    /// An event of a binary file is corrupted
+    BadEvent { line_no: usize, reason: &'static str },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "I/O error: {}", e),
+            ParseError::BadPrefix{line_no, line} => write!(
+                f, "line {}: '{}' starts with neither '+' nor '-'", line_no, line),
+            ParseError::RegexMismatch{line_no, line} => write!(
+                f, "line {}: '{}' is not a valid StackTraceFlow entry", line_no, line),
+            ParseError::BadLineNumber{line_no, line} => write!(
+                f, "line {}: failed to parse a line or column number in '{}'", line_no, line),
+            ParseError::UnbalancedPop{line_no, record, expected} => write!(
+                f, "line {}: '{}' does not match anything on the stack (top is '{}')",
+                line_no, record, expected),
+            ParseError::EmptyStackPop{line_no} => write!(
+                f, "line {}: a function returns while the stack is empty", line_no),
+            ParseError::BadEvent{line_no, reason} => write!(
+                f, "event {}: {}", line_no, reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    /// The '+' or '-' line of a text file whose record could not be parsed
+    pub fn malformed_line(&self) -> Option<&str> {
+        match self {
+            ParseError::RegexMismatch{line, ..}
+                | ParseError::BadLineNumber{line, ..} => Some(line),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(e: std::io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+/// A single entry of the StackTraceFlow file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    /// A function was entered ('+' line)
+    Push(RecordId),
+    /// A function returned ('-' line). Binary files do not repeat the function, hence None
+    Pop(Option<RecordId>),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text"   => Ok(Format::Text),
+            "binary" => Ok(Format::Binary),
+            _        => Err(format!("Unknown format '{}', expected 'text' or 'binary'", s)),
+        }
+    }
+}
+
+/// Source of StackTraceFlow events
+pub trait TraceReader {
+    /// Read the next event. Returns None at the end of the file
+    fn next_event(&mut self) -> Option<Result<Event, ParseError>>;
+
+    /// Records referred to by the events read so far
+    fn records(&self) -> &[Record];
+
+    /// Number of the line (text) or event (binary) returned last
+    fn line_no(&self) -> usize;
+
+    /// Timestamp (in nanoseconds) of the event returned last, if it has one
+    fn timestamp(&self) -> Option<u64>;
+
+    /// Offset in the decompressed file of the event returned last, or of the end of the file
+    /// once it has been reached
+    fn offset(&self) -> u64;
+
+    /// Continue reading from an offset previously returned by `offset`. Only possible for
+    /// uncompressed files. Binary files must have been read to the end before, so that all
+    /// their records are known
+    fn seek(&mut self, offset: u64) -> Result<(), ParseError>;
+
+    /// Take over the records of a previous read of the whole file, e.g. from the index cache,
+    /// so that the reader can seek without reading the file to the end first
+    fn restore_records(&mut self, records: Vec<Record>);
+
+    fn format(&self) -> Format;
+}
+
+/// Open a StackTraceFlow file, recognizing its format and compression automatically
+pub fn open(path: &Path) -> Result<Box<dyn TraceReader>, ParseError> {
+    let mut input = crate::input::open(path)?;
+    if input.fill_buf()?.starts_with(binary::MAGIC) {
+        Ok(Box::new(binary::BinaryReader::new(input)?))
+    } else {
+        Ok(Box::new(TextReader::new(input)))
+    }
+}
+
+/// Format the record the way it appears in the text format, without the '+' or '-' sign
+pub fn format_line(record: &Record) -> String {
+    format!(
+        "{} @{}:{}:{}: {}:{}",
+        record.function, record.file, record.line, record.column,
+        record.last_line, record.last_column,
+    )
+}
+
+/// Lines may end with a timestamp in nanoseconds, separated by a space. The record itself ends
+/// with 'last_line:last_column', so a last field made of digits only must be the timestamp
+fn split_timestamp(line: &str) -> (&str, Option<u64>) {
+    match line.rfind(' ') {
+        Some(pos) if line[pos + 1..].bytes().all(|b| b.is_ascii_digit()) => {
+            match line[pos + 1..].parse() {
+                Ok(timestamp) => (&line[..pos], Some(timestamp)),
+                Err(_) => (line, None),
+            }
+        },
+        _ => (line, None),
+    }
+}
+
+/// Reader of the original, line-based format
+pub struct TextReader {
+    input:   Input,
+    line:    String,
+    line_no: usize,
+    offset:  u64,
+    timestamp: Option<u64>,
+    re:      Regex,
+    ids:     HashMap<String, RecordId>,
+    records: Vec<Record>,
+}
+
+impl TextReader {
+    pub fn new(input: Input) -> Self {
+        TextReader{
+            input: input,
+            line: String::new(),
+            line_no: 0,
+            offset: 0,
+            timestamp: None,
+            re: Regex::new(r"(?x)
+                ^
+                (?P<function>[^@]+)
+                \s@
+                (?P<file>[^:]+)
+                :
+                (?P<line>\d+)
+                :
+                (?P<column>\d+)
+                :\s
+                (?P<last_line>\d+)
+                :
+                (?P<last_column>\d+)
+                $
+            ").expect("Failed to compile the parser's regex"),
+            ids: HashMap::new(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Parse the record of a '+' or '-' line
+    fn parse(&self, line: &str) -> Result<Record, ParseError> {
+        let line_no = self.line_no;
+        let cap = self.re.captures(&line[1..]).ok_or_else(
+            || ParseError::RegexMismatch{line_no, line: line.to_string()})?;
+        let number = |name: &str| cap[name].parse().map_err(
+            |_| ParseError::BadLineNumber{line_no, line: line.to_string()});
+        Ok(Record{
+            function: cap["function"].to_string(),
+            file: cap["file"].to_string(),
+            line: number("line")?,
+            column: number("column")?,
+            last_line: number("last_line")?,
+            last_column: number("last_column")?,
+        })
+    }
+
+    /// Lines repeat a lot, so every distinct one is parsed only once. The sign does not count
+    fn intern(&mut self, line: &str) -> Result<RecordId, ParseError> {
+        if let Some(id) = self.ids.get(&line[1..]) {
+            return Ok(*id);
+        }
+        let record = self.parse(line)?;
+        let id = self.records.len();
+        self.records.push(record);
+        self.ids.insert(line[1..].to_string(), id);
+        Ok(id)
+    }
+}
+
+impl TraceReader for TextReader {
+    fn next_event(&mut self) -> Option<Result<Event, ParseError>> {
+        self.offset = self.input.offset();
+        // Reuse the buffer across lines
+        let mut buffer = std::mem::replace(&mut self.line, String::new());
+        buffer.clear();
+        match self.input.read_line(&mut buffer) {
+            Ok(0) => return None,
+            Ok(_) => {},
+            Err(e) => return Some(Err(e.into())),
+        }
+        self.line_no += 1;
+        let line = buffer.trim_end_matches(|c| c == '\n' || c == '\r');
+        let (line, timestamp) = split_timestamp(line);
+        self.timestamp = timestamp;
+        let event = if line.starts_with("+") {
+            self.intern(line).map(Event::Push)
+        } else if line.starts_with("-") {
+            self.intern(line).map(|id| Event::Pop(Some(id)))
+        } else {
+            Err(ParseError::BadPrefix{line_no: self.line_no, line: line.to_string()})
+        };
+        self.line = buffer;
+        Some(event)
+    }
+
+    fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    fn line_no(&self) -> usize {
+        self.line_no
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), ParseError> {
+        Ok(self.input.seek(offset)?)
+    }
+
+    fn restore_records(&mut self, _records: Vec<Record>) {
+        // Every line carries its record in full, so there is nothing to restore
+    }
+
+    fn format(&self) -> Format {
+        Format::Text
+    }
+}
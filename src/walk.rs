@@ -0,0 +1,338 @@
+//! Visiting the rows hidden inside collapsed nodes
+//!
+//! The tree view only gives access to the rows it shows, so collapsed nodes are expanded while
+//! they are visited and collapsed again afterwards. In lazy mode only the subtrees read so far
+//! are visited.
+
+use crate::data::{Row, TreeType};
+use cursive_tree_view::Placement;
+
+/// Rows of the children of `parent`, or of the top level if it is None
+pub fn children(tree: &TreeType, parent: Option<usize>) -> Vec<usize> {
+    let first = parent.map_or(0, |parent| parent + 1);
+    let mut result = Vec::new();
+    for row in first..tree.len() {
+        let row_parent = tree.item_parent(row);
+        if row_parent == parent {
+            result.push(row);
+        } else if parent.is_some() && row_parent.map_or(true, |p| p < parent.unwrap()) {
+            // Past the subtree of the parent
+            break;
+        }
+    }
+    result
+}
+
+/// Expand the row, remembering it if it was collapsed
+fn expand(tree: &mut TreeType, row: usize, expanded: &mut Vec<usize>) {
+    let len = tree.len();
+    tree.expand_item(row);
+    if tree.len() > len {
+        expanded.push(row);
+    }
+}
+
+/// Visit every item of the tree in order, together with the number of calls it is nested in
+pub fn for_each(tree: &mut TreeType, mut visit: impl FnMut(&TreeType, usize, usize)) {
+    let mut expanded: Vec<usize> = Vec::new();
+    // Rows above the one visited do not move when it is expanded
+    let mut depths: Vec<usize> = Vec::new();
+    let mut row = 0;
+    while row < tree.len() {
+        expand(tree, row, &mut expanded);
+        let depth = match tree.item_parent(row) {
+            Some(parent) => match tree.borrow_item(parent) {
+                Some(Row::Thread(_)) => depths[parent],
+                _ => depths[parent] + 1,
+            },
+            None => 0,
+        };
+        depths.push(depth);
+        visit(tree, row, depth);
+        row += 1;
+    }
+    for row in expanded.into_iter().rev() {
+        tree.collapse_item(row);
+    }
+}
+
+/// Expand the row like `expand`, keeping the rows remembered in `expanded` up to date when
+/// they are below it
+fn expand_above(tree: &mut TreeType, row: usize, expanded: &mut Vec<usize>) {
+    let len = tree.len();
+    tree.expand_item(row);
+    let added = tree.len() - len;
+    if added > 0 {
+        for other in expanded.iter_mut().filter(|other| **other > row) {
+            *other += added;
+        }
+        expanded.push(row);
+    }
+}
+
+/// Expand the rows right above `row`, which may also be the end of the tree, until the one
+/// above it has no hidden children. That one is then the row before it in the fully expanded
+/// tree. Returns where `row` has moved
+fn uncover_previous(tree: &mut TreeType, mut row: usize, expanded: &mut Vec<usize>) -> usize {
+    while row > 0 {
+        let len = tree.len();
+        expand_above(tree, row - 1, expanded);
+        if tree.len() == len {
+            break;
+        }
+        row += tree.len() - len;
+    }
+    row
+}
+
+/// The first row after `from` for which `found` is true, then the first one from the top
+fn find_next(
+    tree: &mut TreeType,
+    from: Option<usize>,
+    found: &mut dyn FnMut(&TreeType, usize) -> bool,
+    expanded: &mut Vec<usize>,
+) -> Option<usize> {
+    let mut row = match from {
+        Some(from) => {
+            expand_above(tree, from, expanded);
+            from + 1
+        },
+        None => 0,
+    };
+    while row < tree.len() {
+        if found(tree, row) {
+            return Some(row);
+        }
+        expand_above(tree, row, expanded);
+        row += 1;
+    }
+    // Wrap around, up to `from` itself
+    let mut from = from?;
+    row = 0;
+    while row <= from {
+        if found(tree, row) {
+            return Some(row);
+        }
+        let len = tree.len();
+        expand_above(tree, row, expanded);
+        if row < from {
+            from += tree.len() - len;
+        }
+        row += 1;
+    }
+    None
+}
+
+/// The last row before `from` for which `found` is true, then the last one from the bottom
+fn find_previous(
+    tree: &mut TreeType,
+    from: Option<usize>,
+    found: &mut dyn FnMut(&TreeType, usize) -> bool,
+    expanded: &mut Vec<usize>,
+) -> Option<usize> {
+    let mut from = from;
+    let mut row = from.unwrap_or(tree.len());
+    loop {
+        let moved = uncover_previous(tree, row, expanded);
+        // Only the rows above `row` were expanded, so `from` moved as far
+        from = from.map(|from| from + moved - row);
+        row = moved;
+        if row == 0 {
+            break;
+        }
+        row -= 1;
+        if found(tree, row) {
+            return Some(row);
+        }
+    }
+    // Wrap around, up to `from` itself. The rows expanded now are not above it
+    let from = from?;
+    let mut row = tree.len();
+    loop {
+        row = uncover_previous(tree, row, expanded) - 1;
+        if found(tree, row) {
+            return Some(row);
+        }
+        if row == from {
+            return None;
+        }
+    }
+}
+
+/// Find the next row after `from` for which `found` is true, wrapping around at the end of the
+/// tree, or the previous one if not `forward`. With no `from`, find the first (or the last) of
+/// them. The search stops at the first row found. Its ancestors are expanded so that it is
+/// shown, everything else is left collapsed as it was
+pub fn reveal(
+    tree: &mut TreeType,
+    from: Option<usize>,
+    forward: bool,
+    mut found: impl FnMut(&TreeType, usize) -> bool,
+) -> Option<usize> {
+    let mut expanded: Vec<usize> = Vec::new();
+    let mut result = if forward {
+        find_next(tree, from, &mut found, &mut expanded)
+    } else {
+        find_previous(tree, from, &mut found, &mut expanded)
+    };
+
+    let mut ancestors: Vec<usize> = Vec::new();
+    let mut parent = result.and_then(|row| tree.item_parent(row));
+    while let Some(row) = parent {
+        ancestors.push(row);
+        parent = tree.item_parent(row);
+    }
+    // Collapsing a row only moves the rows below it, so the lowest ones go first
+    expanded.sort_unstable();
+    for row in expanded.into_iter().rev() {
+        if ancestors.contains(&row) {
+            continue;
+        }
+        let len = tree.len();
+        tree.collapse_item(row);
+        result = result.map(|found| if row < found { found - (len - tree.len()) } else { found });
+    }
+    result
+}
+
+/// The rows of a tree in order, each with the position of its parent and whether it was
+/// collapsed. Unlike the tree view, which holds callbacks, it can be sent to another thread
+pub type Rows = Vec<(Option<usize>, Row, bool)>;
+
+/// Take all the rows out of the tree, including those inside collapsed nodes
+pub fn take(mut tree: TreeType) -> Rows {
+    let mut rows = Vec::new();
+    let mut row = 0;
+    while row < tree.len() {
+        let len = tree.len();
+        tree.expand_item(row);
+        let collapsed = tree.len() > len;
+        if let Some(item) = tree.borrow_item(row) {
+            rows.push((tree.item_parent(row), item.clone(), collapsed));
+        }
+        row += 1;
+    }
+    rows
+}
+
+/// Put the rows taken by `take` into an empty tree, collapsing them as they were
+pub fn fill(tree: &mut TreeType, rows: Rows) {
+    let mut collapsed = Vec::new();
+    let mut previous = None;
+    for (row, (parent, item, was_collapsed)) in rows.into_iter().enumerate() {
+        // Nothing is collapsed yet, so the rows are inserted where they were taken from
+        match (parent, previous) {
+            (Some(parent), _) => tree.insert_item(item, Placement::LastChild, parent),
+            (None, None) => tree.insert_item(item, Placement::LastChild, 0),
+            (None, Some(previous)) => tree.insert_item(item, Placement::After, previous),
+        };
+        if parent.is_none() {
+            previous = Some(row);
+        }
+        if was_collapsed {
+            collapsed.push(row);
+        }
+    }
+    // Collapsing a row only moves the rows below it
+    for row in collapsed.into_iter().rev() {
+        tree.collapse_item(row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Item, Record};
+    use cursive_tree_view::Placement;
+
+    fn call(function: &str) -> Row {
+        Row::Call(Item::from(Record{
+            function: function.to_owned(),
+            file: "src/lib.rs".to_owned(),
+            line: 1,
+            column: 0,
+            last_line: 0,
+            last_column: 0,
+        }))
+    }
+
+    /// a [b [c [x]], x], d [x], with b and d collapsed
+    fn sample() -> TreeType {
+        let mut tree = TreeType::new();
+        tree.insert_item(call("a"), Placement::LastChild, 0);
+        tree.insert_item(call("b"), Placement::LastChild, 0);
+        tree.insert_item(call("c"), Placement::LastChild, 1);
+        tree.insert_item(call("x"), Placement::LastChild, 2);
+        tree.insert_item(call("x"), Placement::LastChild, 0);
+        tree.insert_item(call("d"), Placement::After, 0);
+        tree.insert_item(call("x"), Placement::LastChild, 5);
+        tree.collapse_item(5);
+        tree.collapse_item(1);
+        tree
+    }
+
+    fn is(function: &'static str) -> impl FnMut(&TreeType, usize) -> bool {
+        move |tree, row| {
+            tree.borrow_item(row).and_then(Row::record).map_or(false, |r| r.function == function)
+        }
+    }
+
+    fn functions(tree: &TreeType) -> Vec<String> {
+        (0..tree.len()).map(|row| tree.borrow_item(row).and_then(Row::record).unwrap())
+            .map(|record| record.function.clone()).collect()
+    }
+
+    #[test]
+    fn reveal_expands_only_the_path_to_the_row_found() {
+        let mut tree = sample();
+        assert_eq!(reveal(&mut tree, None, true, is("x")), Some(3));
+        assert_eq!(functions(&tree), vec!["a", "b", "c", "x", "x", "d"]);
+    }
+
+    #[test]
+    fn reveal_finds_the_next_row_in_a_collapsed_node() {
+        let mut tree = sample();
+        assert_eq!(reveal(&mut tree, Some(2), true, is("x")), Some(4));
+        assert_eq!(functions(&tree), vec!["a", "b", "x", "d", "x"]);
+    }
+
+    #[test]
+    fn reveal_finds_the_previous_row_in_a_collapsed_node() {
+        let mut tree = sample();
+        assert_eq!(reveal(&mut tree, Some(2), false, is("x")), Some(3));
+        assert_eq!(functions(&tree), vec!["a", "b", "c", "x", "x", "d"]);
+    }
+
+    #[test]
+    fn reveal_wraps_around() {
+        let mut tree = sample();
+        assert_eq!(reveal(&mut tree, Some(0), false, is("x")), Some(4));
+        assert_eq!(functions(&tree), vec!["a", "b", "x", "d", "x"]);
+
+        let mut tree = sample();
+        assert_eq!(reveal(&mut tree, Some(3), true, is("c")), Some(2));
+        assert_eq!(functions(&tree), vec!["a", "b", "c", "x", "x", "d"]);
+    }
+
+    #[test]
+    fn reveal_leaves_the_tree_as_it_was_without_a_match() {
+        let mut tree = sample();
+        assert_eq!(reveal(&mut tree, Some(2), true, is("y")), None);
+        assert_eq!(functions(&tree), vec!["a", "b", "x", "d"]);
+        assert_eq!(reveal(&mut tree, Some(2), false, is("y")), None);
+        assert_eq!(functions(&tree), vec!["a", "b", "x", "d"]);
+    }
+
+    #[test]
+    fn filling_a_tree_with_the_rows_taken_keeps_it_as_it_was() {
+        let mut tree = TreeType::new();
+        fill(&mut tree, take(sample()));
+        assert_eq!(functions(&tree), vec!["a", "b", "x", "d"]);
+        assert_eq!(tree.item_parent(2), Some(0));
+        tree.expand_item(1);
+        tree.expand_item(5);
+        assert_eq!(functions(&tree), vec!["a", "b", "c", "x", "x", "d", "x"]);
+        assert_eq!(tree.item_parent(3), Some(2));
+        assert_eq!(tree.item_parent(6), Some(5));
+    }
+}